@@ -1,26 +1,76 @@
 use base64::{engine::general_purpose, Engine};
 use ic_cdk::api::{certified_data_set, data_certificate};
-use ic_certified_map::{labeled, labeled_hash, AsHashTree};
+use ic_certified_map::{fork, fork_hash, labeled, labeled_hash, AsHashTree, Hash, HashTree};
+use serde::Serialize;
+use sha2::{Digest, Sha256};
 
-use crate::types::assets::AssetHashes;
-use crate::types::http::HeaderField;
+use crate::types::assets::{AssetHashes, NestedTree, NestedTreeNode};
+use crate::types::http::{HeaderField, ServerError};
 
 const LABEL_ASSETS: &[u8] = b"http_assets";
+const LABEL_EXPR: &str = "http_expr";
 
+// Terminate the paths of the v2 tree: the responses of an exact url, or of all the urls under
+// the path that match no more specific path
+const EXACT_PATH_TERMINATOR: &str = "<$>";
+const WILDCARD_PATH_TERMINATOR: &str = "<*>";
+
+const IC_CERTIFICATE_HEADER: &str = "IC-Certificate";
+const IC_CERTIFICATE_EXPRESSION_HEADER: &str = "IC-CertificateExpression";
+
+// The request is not certified and every header of the response but IC-Certificate is
+const CERTIFICATE_EXPRESSION: &str = "default_certification(ValidationArgs{certification:Certification{no_request_certification:Empty{},response_certification:ResponseCertification{response_header_exclusions:ResponseHeaderList{headers:[]}}}})";
+
+const RESPONSE_STATUS_PSEUDO_HEADER: &str = ":ic-cert-status";
+
+/// The certified data is the root of a tree holding both the v1 (`http_assets`) and the v2
+/// (`http_expr`) certification, so that older gateways can still verify the responses.
 pub fn update_certified_data(asset_hashes: &AssetHashes) {
-    let prefixed_root_hash = &labeled_hash(LABEL_ASSETS, &asset_hashes.tree.root_hash());
-    certified_data_set(&prefixed_root_hash[..]);
+    let root_hash = fork_hash(
+        &assets_root_hash(asset_hashes),
+        &expr_root_hash(asset_hashes),
+    );
+    certified_data_set(&root_hash[..]);
+}
+
+fn assets_root_hash(asset_hashes: &AssetHashes) -> Hash {
+    labeled_hash(LABEL_ASSETS, &asset_hashes.tree.root_hash())
+}
+
+fn expr_root_hash(asset_hashes: &AssetHashes) -> Hash {
+    labeled_hash(LABEL_EXPR.as_bytes(), &asset_hashes.expr_tree.root_hash())
+}
+
+/// Where the response for a url is certified: under the url itself, or under the fallback of
+/// the root when the url matches no asset.
+pub enum CertifiedPath {
+    Exact(String),
+    Fallback(String),
 }
 
+/// Header of the response certified for the `url`. Gateways that request the certificate
+/// version 2 receive a v2 witness of the `response_hash`, others a v1 witness of the content.
 pub fn build_asset_certificate_header(
     asset_hashes: &AssetHashes,
-    url: &str,
-) -> Result<HeaderField, &'static str> {
+    certified_path: &CertifiedPath,
+    response_hash: &Hash,
+    certificate_version: Option<u16>,
+) -> Result<HeaderField, ServerError> {
     let certificate = data_certificate();
 
     match certificate {
-        None => Err("No certificate found."),
-        Some(certificate) => build_asset_certificate_header_impl(&certificate, asset_hashes, url),
+        None => Err(ServerError::CertificateUnavailable),
+        Some(certificate) => match (certificate_version, certified_path) {
+            (Some(version), _) if version >= 2 => build_asset_certificate_header_v2(
+                &certificate,
+                asset_hashes,
+                certified_path,
+                response_hash,
+            ),
+            (_, CertifiedPath::Exact(url) | CertifiedPath::Fallback(url)) => {
+                build_asset_certificate_header_impl(&certificate, asset_hashes, url)
+            }
+        },
     }
 }
 
@@ -28,10 +78,76 @@ fn build_asset_certificate_header_impl(
     certificate: &Vec<u8>,
     asset_hashes: &AssetHashes,
     url: &str,
-) -> Result<HeaderField, &'static str> {
+) -> Result<HeaderField, ServerError> {
     let witness = asset_hashes.tree.witness(url.as_bytes());
-    let tree = labeled(LABEL_ASSETS, witness);
+    let tree = fork(
+        labeled(LABEL_ASSETS, witness),
+        HashTree::Pruned(expr_root_hash(asset_hashes)),
+    );
+
+    Ok(HeaderField(
+        IC_CERTIFICATE_HEADER.to_string(),
+        format!(
+            "certificate=:{}:, tree=:{}:",
+            general_purpose::STANDARD_NO_PAD.encode(certificate),
+            general_purpose::STANDARD_NO_PAD.encode(serialize_cbor(&tree)?)
+        ),
+    ))
+}
+
+fn build_asset_certificate_header_v2(
+    certificate: &Vec<u8>,
+    asset_hashes: &AssetHashes,
+    certified_path: &CertifiedPath,
+    response_hash: &Hash,
+) -> Result<HeaderField, ServerError> {
+    let path = match certified_path {
+        CertifiedPath::Exact(url) => expr_path(url),
+        CertifiedPath::Fallback(_) => fallback_expr_path(),
+    };
+
+    let response_witness = nested_witness(
+        &asset_hashes.expr_tree,
+        &[expr_labels(&path), response_path(response_hash)].concat(),
+    );
+
+    // A fallback response is valid only if no more specific path certifies the url
+    let witness = match certified_path {
+        CertifiedPath::Exact(_) => response_witness,
+        CertifiedPath::Fallback(url) => {
+            more_specific_expr_paths(url)
+                .iter()
+                .fold(response_witness, |witness, path| {
+                    merge_hash_trees(
+                        witness,
+                        nested_witness(&asset_hashes.expr_tree, &expr_labels(path)),
+                    )
+                })
+        }
+    };
+
+    let tree = fork(
+        HashTree::Pruned(assets_root_hash(asset_hashes)),
+        labeled(LABEL_EXPR.as_bytes(), witness),
+    );
+
+    let expr_path: Vec<&str> = [LABEL_EXPR]
+        .into_iter()
+        .chain(path.iter().map(String::as_str))
+        .collect();
+
+    Ok(HeaderField(
+        IC_CERTIFICATE_HEADER.to_string(),
+        format!(
+            "certificate=:{}:, tree=:{}:, expr_path=:{}:, version=2",
+            general_purpose::STANDARD_NO_PAD.encode(certificate),
+            general_purpose::STANDARD_NO_PAD.encode(serialize_cbor(&tree)?),
+            general_purpose::STANDARD_NO_PAD.encode(serialize_cbor(&expr_path)?)
+        ),
+    ))
+}
 
+fn serialize_cbor<T: Serialize>(value: &T) -> Result<Vec<u8>, ServerError> {
     //
     // @Gabriel
     // I changed this to use ciborium as its maintained and doesn't give us
@@ -40,14 +156,299 @@ fn build_asset_certificate_header_impl(
 
     let mut writer = Vec::<u8>::new();
 
-    ciborium::ser::into_writer(&tree, &mut writer).map_err(|_| "failed to serialize hash tree")?;
+    ciborium::ser::into_writer(value, &mut writer).map_err(|_| ServerError::CertificateEncoding)?;
 
-    Ok(HeaderField(
-        "IC-Certificate".to_string(),
-        format!(
-            "certificate=:{}:, tree=:{}:",
-            general_purpose::STANDARD_NO_PAD.encode(certificate),
-            general_purpose::STANDARD_NO_PAD.encode(writer)
-        ),
-    ))
+    Ok(writer)
+}
+
+pub fn build_certificate_expression_header() -> HeaderField {
+    HeaderField(
+        IC_CERTIFICATE_EXPRESSION_HEADER.to_string(),
+        CERTIFICATE_EXPRESSION.to_string(),
+    )
+}
+
+//
+// v2 - http_expr tree
+//
+
+/// Path of the responses of an exact url in the `http_expr` tree: one label per segment of the
+/// url followed by the terminator. "/" is the single empty segment.
+pub fn expr_path(url: &str) -> Vec<String> {
+    [url_segments(url), vec![EXACT_PATH_TERMINATOR.to_string()]].concat()
+}
+
+/// Path of the responses to the urls that match no asset
+pub fn fallback_expr_path() -> Vec<String> {
+    vec![WILDCARD_PATH_TERMINATOR.to_string()]
+}
+
+/// Paths that would certify the url before the fallback of the root, from the most specific:
+/// the exact url, then the wildcard of each of its parents both as a file and as a folder, as
+/// the gateways walk them up to the root.
+fn more_specific_expr_paths(url: &str) -> Vec<Vec<String>> {
+    let mut paths = vec![expr_path(url)];
+    let mut segments = url_segments(url);
+
+    while !segments.is_empty() {
+        paths.push([segments.clone(), vec![WILDCARD_PATH_TERMINATOR.to_string()]].concat());
+
+        // "/a/b" is the file "b" of the folder "/a/", which is then "/a"
+        if segments.pop().is_some_and(|segment| !segment.is_empty()) {
+            segments.push(String::new());
+        }
+    }
+
+    paths
+}
+
+fn url_segments(url: &str) -> Vec<String> {
+    url.trim_start_matches('/')
+        .split('/')
+        .map(str::to_string)
+        .collect()
+}
+
+pub fn expr_labels(path: &[String]) -> Vec<Vec<u8>> {
+    path.iter()
+        .map(|segment| segment.as_bytes().to_vec())
+        .collect()
+}
+
+/// Labels under the expression path that lead to a certified response: the hash of the
+/// expression, the empty request hash (the request is not certified) and the response hash.
+pub fn response_path(response_hash: &Hash) -> Vec<Vec<u8>> {
+    vec![
+        sha256(CERTIFICATE_EXPRESSION.as_bytes()).to_vec(),
+        Vec::new(),
+        response_hash.to_vec(),
+    ]
+}
+
+/// Hash of the response: its headers, except IC-Certificate, and its status code are hashed
+/// independently of their order, then hashed together with the hash of the body.
+pub fn response_hash(headers: &[HeaderField], status_code: u16, body_hash: &Hash) -> Hash {
+    let mut fields: Vec<Vec<u8>> = headers
+        .iter()
+        .filter(|HeaderField(name, _)| !name.eq_ignore_ascii_case(IC_CERTIFICATE_HEADER))
+        .map(|HeaderField(name, value)| {
+            [
+                sha256(name.to_ascii_lowercase().as_bytes()),
+                sha256(value.as_bytes()),
+            ]
+            .concat()
+        })
+        .collect();
+
+    fields.push(
+        [
+            sha256(RESPONSE_STATUS_PSEUDO_HEADER.as_bytes()),
+            sha256(&leb128(status_code.into())),
+        ]
+        .concat(),
+    );
+
+    fields.sort();
+
+    sha256(&[sha256(&fields.concat()), *body_hash].concat())
+}
+
+fn sha256(data: &[u8]) -> Hash {
+    Sha256::digest(data).into()
+}
+
+fn leb128(mut value: u64) -> Vec<u8> {
+    let mut bytes = Vec::new();
+
+    loop {
+        let byte = (value & 0x7f) as u8;
+        value >>= 7;
+
+        if value == 0 {
+            bytes.push(byte);
+            return bytes;
+        }
+
+        bytes.push(byte | 0x80);
+    }
+}
+
+pub fn insert_nested(tree: &mut NestedTree, path: &[Vec<u8>], value: Vec<u8>) {
+    match path {
+        [] => (),
+        [key] => tree.insert(key.clone(), NestedTreeNode::Leaf(value)),
+        [key, rest @ ..] => {
+            if !matches!(tree.get(key), Some(NestedTreeNode::Nested(_))) {
+                tree.insert(key.clone(), NestedTreeNode::Nested(NestedTree::new()));
+            }
+
+            tree.modify(key, |node| {
+                if let NestedTreeNode::Nested(subtree) = node {
+                    insert_nested(subtree, rest, value);
+                }
+            });
+        }
+    }
+}
+
+/// Deletes the node at the end of the path and the nodes the deletion leaves empty
+pub fn delete_nested(tree: &mut NestedTree, path: &[Vec<u8>]) {
+    match path {
+        [] => (),
+        [key] => tree.delete(key),
+        [key, rest @ ..] => {
+            let mut empty = false;
+
+            tree.modify(key, |node| {
+                if let NestedTreeNode::Nested(subtree) = node {
+                    delete_nested(subtree, rest);
+                    empty = subtree.is_empty();
+                }
+            });
+
+            if empty {
+                tree.delete(key);
+            }
+        }
+    }
+}
+
+fn nested_witness<'a>(tree: &'a NestedTree, path: &[Vec<u8>]) -> HashTree<'a> {
+    match path {
+        [] => tree.as_hash_tree(),
+        [key] => tree.witness(key),
+        [key, rest @ ..] => tree.nested_witness(key, |node| match node {
+            NestedTreeNode::Nested(subtree) => nested_witness(subtree, rest),
+            NestedTreeNode::Leaf(_) => node.as_hash_tree(),
+        }),
+    }
+}
+
+/// Combines two witnesses of the same tree, keeping the nodes revealed by either of them
+fn merge_hash_trees<'a>(lhs: HashTree<'a>, rhs: HashTree<'a>) -> HashTree<'a> {
+    match (lhs, rhs) {
+        (HashTree::Pruned(_), tree) | (tree, HashTree::Pruned(_)) => tree,
+        (HashTree::Fork(lhs), HashTree::Fork(rhs)) => {
+            let (lhs_left, lhs_right) = *lhs;
+            let (rhs_left, rhs_right) = *rhs;
+
+            fork(
+                merge_hash_trees(lhs_left, rhs_left),
+                merge_hash_trees(lhs_right, rhs_right),
+            )
+        }
+        (HashTree::Labeled(label, lhs), HashTree::Labeled(_, rhs)) => {
+            labeled(label, merge_hash_trees(*lhs, *rhs))
+        }
+        (lhs, _) => lhs,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{expr_path, leb128, more_specific_expr_paths, response_hash, sha256};
+    use crate::types::http::HeaderField;
+
+    fn path(segments: &[&str]) -> Vec<String> {
+        segments.iter().map(|segment| segment.to_string()).collect()
+    }
+
+    fn header(name: &str, value: &str) -> HeaderField {
+        HeaderField(name.to_string(), value.to_string())
+    }
+
+    #[test]
+    fn expr_paths_end_with_the_exact_terminator() {
+        assert_eq!(expr_path("/"), path(&["", "<$>"]));
+        assert_eq!(expr_path("/a/b"), path(&["a", "b", "<$>"]));
+        assert_eq!(expr_path("/docs/"), path(&["docs", "", "<$>"]));
+    }
+
+    #[test]
+    fn more_specific_paths_of_the_root() {
+        assert_eq!(
+            more_specific_expr_paths("/"),
+            vec![path(&["", "<$>"]), path(&["", "<*>"])]
+        );
+    }
+
+    #[test]
+    fn more_specific_paths_of_a_file() {
+        assert_eq!(
+            more_specific_expr_paths("/a/b"),
+            vec![
+                path(&["a", "b", "<$>"]),
+                path(&["a", "b", "<*>"]),
+                path(&["a", "", "<*>"]),
+                path(&["a", "<*>"]),
+                path(&["", "<*>"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn more_specific_paths_of_a_folder() {
+        assert_eq!(
+            more_specific_expr_paths("/docs/"),
+            vec![
+                path(&["docs", "", "<$>"]),
+                path(&["docs", "", "<*>"]),
+                path(&["docs", "<*>"]),
+                path(&["", "<*>"]),
+            ]
+        );
+    }
+
+    #[test]
+    fn leb128_encodes_seven_bits_per_byte() {
+        assert_eq!(leb128(0), vec![0x00]);
+        assert_eq!(leb128(127), vec![0x7f]);
+        assert_eq!(leb128(128), vec![0x80, 0x01]);
+        assert_eq!(leb128(200), vec![0xc8, 0x01]);
+        assert_eq!(leb128(304), vec![0xb0, 0x02]);
+        assert_eq!(leb128(624_485), vec![0xe5, 0x8e, 0x26]);
+    }
+
+    // Vectors of ic-http-certification, the reference of the gateways
+
+    #[test]
+    fn response_hash_ignores_the_certificate_header() {
+        let headers = vec![
+            header(
+                "IC-CertificateExpression",
+                "default_certification(ValidationArgs{certification:Certification{no_request_certification:Empty{},response_certification:ResponseCertification{response_header_exclusions:ResponseHeaderList{headers:[\"Content-Security-Policy\"]}}}})",
+            ),
+            header("Content-Security-Policy", "default-src 'self'"),
+            header("IC-Certificate", "certificate=:AAAA:, tree=:AAAA:"),
+        ];
+
+        assert_eq!(
+            hex::encode(response_hash(&headers, 200, &sha256(b"Hello World!"))),
+            "a2ffb50ef8971650c2fb46c0a2788b7d5ac5a027d635175e8e06b419ce6c4cda"
+        );
+    }
+
+    #[test]
+    fn response_hash_keeps_repeated_headers() {
+        let headers = vec![
+            header(
+                "IC-CertificateExpression",
+                "default_certification(ValidationArgs{certification:Certification{no_request_certification:Empty{},response_certification:ResponseCertification{certified_response_headers:ResponseHeaderList{headers:[\"Accept-Encoding\",\"Cache-Control\"]}}}})",
+            ),
+            header("Accept-Encoding", "gzip"),
+            header("Cache-Control", "no-cache"),
+            header("Cache-Control", "no-store"),
+        ];
+
+        let body_hash: [u8; 32] =
+            hex::decode("5462fc394013080effc31d578ec3fff8b44cdf24738b38a77ce4afacbc93a7f5")
+                .unwrap()
+                .try_into()
+                .unwrap();
+
+        assert_eq!(
+            hex::encode(response_hash(&headers, 200, &body_hash)),
+            "1afc744a377cb8785d1078f53f9bbc9160d86b7a05f490e42c89366326eaef20"
+        );
+    }
 }
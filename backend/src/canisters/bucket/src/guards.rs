@@ -0,0 +1,43 @@
+use candid::Principal;
+use ic_cdk::api::{is_controller, msg_caller};
+
+use crate::types::state::{HeapState, Role};
+use crate::STATE;
+
+//
+// Guards applied to the canister endpoints
+//
+
+pub fn caller_is_owner() -> Result<(), String> {
+    caller_has_role(Role::Owner)
+}
+
+pub fn caller_can_upload() -> Result<(), String> {
+    caller_has_role(Role::Uploader)
+}
+
+pub fn caller_can_read() -> Result<(), String> {
+    caller_has_role(Role::Reader)
+}
+
+fn caller_has_role(role: Role) -> Result<(), String> {
+    let caller = msg_caller();
+    let granted = STATE.with(|state| principal_role(&caller, &state.borrow().heap));
+
+    match granted {
+        Some(granted) if granted >= role => Ok(()),
+        _ => Err(format!(
+            "Caller {caller} is not authorized. {role:?} role required."
+        )),
+    }
+}
+
+/// The user of the bucket and the controllers of the canister are always owners, other principals
+/// have the role they were granted.
+pub fn principal_role(principal: &Principal, state: &HeapState) -> Option<Role> {
+    if state.user == Some(*principal) || is_controller(principal) {
+        return Some(Role::Owner);
+    }
+
+    state.permissions.get(principal).copied()
+}
@@ -1,69 +1,738 @@
-use crate::cert::build_asset_certificate_header;
-use crate::types::http::{CallbackFunc, HeaderField, StreamingCallbackToken, StreamingStrategy};
-use crate::types::state::RuntimeState;
-use crate::types::store::{Asset, AssetEncoding, AssetKey};
+use crate::cert::{
+    build_asset_certificate_header, build_certificate_expression_header, response_hash,
+    CertifiedPath,
+};
+use crate::impls::{
+    ASSET_ENCODING_KEYS_PREFERENCE, ASSET_ENCODING_KEY_BROTLI, ASSET_ENCODING_KEY_RAW,
+};
+use crate::routing::fallback_status;
+use crate::store::{get_cache_control_rules, get_content_chunk, get_fallback_asset, get_routing};
+use crate::types::http::{
+    CallbackFunc, HeaderField, HttpResponse, RequestUrl, ServerError, StreamingCallbackToken,
+    StreamingRange, StreamingStrategy,
+};
+use crate::types::state::{CacheControlRule, RuntimeState};
+use crate::types::store::{Asset, AssetEncoding, AssetKey, GetAssetError};
+use crate::url::parse_url;
 use crate::STATE;
 use candid::define_function;
+use httpdate::{fmt_http_date, parse_http_date};
 use ic_cdk::api::canister_self;
+use ic_certified_map::Hash;
 use serde_bytes::ByteBuf;
+use sha2::{Digest, Sha256};
+use std::cmp::min;
+use std::collections::HashMap;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
 
 // Define a new function reference type for http_request_streaming_callback
 define_function!(HttpRequestStreamingCallback : () -> ());
 
-pub fn streaming_strategy(
-    key: &AssetKey,
+#[derive(Debug, PartialEq)]
+enum RequestedRange {
+    Full,
+    // Index of the chunk served as the partial content
+    Partial(usize),
+    Unsatisfiable,
+}
+
+pub fn build_asset_response(
+    asset: &Asset,
+    url: &str,
+    request_headers: &[HeaderField],
+    certificate_version: Option<u16>,
+) -> HttpResponse {
+    let RequestUrl { path, params } = parse_url(url).unwrap_or_else(|_| RequestUrl {
+        path: "/".to_string(),
+        params: HashMap::new(),
+    });
+
+    build_response(
+        asset,
+        &CertifiedPath::Exact(path),
+        200,
+        params.get("token"),
+        request_headers,
+        certificate_version,
+    )
+}
+
+/// Certified response for a url that serves no asset: the fallback asset or a plain error
+pub fn build_error_response(
+    error: GetAssetError,
+    url: &str,
+    request_headers: &[HeaderField],
+    certificate_version: Option<u16>,
+) -> HttpResponse {
+    let url = certified_url(url);
+
+    match error {
+        GetAssetError::NotFound(_) => match get_fallback_asset() {
+            Some(asset) => build_response(
+                &asset,
+                &CertifiedPath::Fallback(url),
+                fallback_status(&get_routing()),
+                None,
+                request_headers,
+                certificate_version,
+            ),
+            None => build_certified_error_response(
+                404,
+                &CertifiedPath::Fallback(url),
+                certificate_version,
+            ),
+        },
+        // The url of a protected asset is certified, an invalid token included
+        GetAssetError::Forbidden(_) => {
+            build_certified_error_response(403, &CertifiedPath::Exact(url), certificate_version)
+        }
+        GetAssetError::BadRequest(_) => {
+            build_certified_error_response(400, &CertifiedPath::Fallback(url), certificate_version)
+        }
+    }
+}
+
+fn build_response(
+    asset: &Asset,
+    certified_path: &CertifiedPath,
+    status_code: u16,
+    token: Option<&String>,
+    request_headers: &[HeaderField],
+    certificate_version: Option<u16>,
+) -> HttpResponse {
+    let encoding_type = select_encoding_type(asset, request_headers, certificate_version);
+    let encoding = asset
+        .encodings
+        .get(&encoding_type)
+        .unwrap_or_else(|| asset.encoding_raw());
+
+    let asset_headers = build_asset_headers(asset, &encoding_type, &get_cache_control_rules());
+
+    let is_exact = matches!(certified_path, CertifiedPath::Exact(_));
+
+    // A revalidation of the asset itself is answered without its content
+    if is_exact && is_not_modified(request_headers, encoding) {
+        return build_not_modified_response(asset_headers, certified_path, certificate_version);
+    }
+
+    // The partial contents are certified for the asset itself and in v2 only, a fallback and
+    // the responses to the gateways that verify v1 are served as a whole
+    let range = match is_exact && certificate_version.is_some_and(|version| version >= 2) {
+        true => requested_range(request_headers, encoding),
+        false => RequestedRange::Full,
+    };
+
+    match range {
+        RequestedRange::Partial(index) => {
+            return build_partial_content_response(
+                asset_headers,
+                certified_path,
+                encoding,
+                index,
+                certificate_version,
+            );
+        }
+        RequestedRange::Unsatisfiable => {
+            return build_range_not_satisfiable_response(
+                encoding,
+                certified_path,
+                certificate_version,
+            );
+        }
+        RequestedRange::Full => (),
+    }
+
+    let headers = match build_headers(
+        asset_headers,
+        certified_path,
+        status_code,
+        &encoding.sha256,
+        certificate_version,
+    ) {
+        Err(error) => return server_error_response(error),
+        Ok(headers) => headers,
+    };
+
+    match get_content_chunk(encoding, 0) {
+        Err(_) => server_error_response(ServerError::ContentMissing),
+        Ok(body) => HttpResponse {
+            streaming_strategy: streaming_strategy(create_token(
+                &asset.key,
+                token,
+                &encoding_type,
+                remaining_range(encoding, body.len()),
+                encoding,
+                &headers,
+            )),
+            body,
+            headers,
+            status_code,
+        },
+    }
+}
+
+fn build_partial_content_response(
+    asset_headers: Vec<HeaderField>,
+    certified_path: &CertifiedPath,
     encoding: &AssetEncoding,
+    index: usize,
+    certificate_version: Option<u16>,
+) -> HttpResponse {
+    let Some(chunk_sha256) = encoding.chunk_sha256s.get(index) else {
+        return server_error_response(ServerError::ContentMissing);
+    };
+
+    let headers = match build_headers(
+        partial_content_headers(asset_headers, encoding, index),
+        certified_path,
+        206,
+        chunk_sha256,
+        certificate_version,
+    ) {
+        Err(error) => return server_error_response(error),
+        Ok(headers) => headers,
+    };
+
+    match get_content_chunk(encoding, index) {
+        Err(_) => server_error_response(ServerError::ContentMissing),
+        Ok(body) => HttpResponse {
+            body,
+            headers,
+            status_code: 206,
+            streaming_strategy: None,
+        },
+    }
+}
+
+/// Hashes of the partial contents certified for an encoding, one per chunk, `headers` being
+/// those of the encoding
+pub fn partial_content_response_hashes(
     headers: &[HeaderField],
-) -> Option<StreamingStrategy> {
-    let streaming_token: Option<StreamingCallbackToken> = create_token(key, 0, encoding, headers);
+    encoding: &AssetEncoding,
+) -> Vec<Hash> {
+    encoding
+        .chunk_lengths
+        .iter()
+        .zip(&encoding.chunk_sha256s)
+        .enumerate()
+        .filter(|(_, (length, _))| **length > 0)
+        .map(|(index, (_, chunk_sha256))| {
+            let headers = partial_content_headers(headers.to_vec(), encoding, index);
+            response_hash(&headers, 206, chunk_sha256)
+        })
+        .collect()
+}
+
+// The chunk at `index` is the partial content, whatever the end of the requested range
+fn partial_content_headers(
+    headers: Vec<HeaderField>,
+    encoding: &AssetEncoding,
+    index: usize,
+) -> Vec<HeaderField> {
+    let start: u128 = encoding.chunk_lengths[..index].iter().sum();
+    let end = start + encoding.chunk_lengths[index] - 1;
+
+    [
+        headers,
+        vec![HeaderField(
+            "Content-Range".to_string(),
+            format!("bytes {}-{}/{}", start, end, encoding.total_length),
+        )],
+    ]
+    .concat()
+}
+
+fn build_range_not_satisfiable_response(
+    encoding: &AssetEncoding,
+    certified_path: &CertifiedPath,
+    certificate_version: Option<u16>,
+) -> HttpResponse {
+    build_message_response(
+        416,
+        range_not_satisfiable_headers(encoding),
+        certified_path,
+        certificate_version,
+    )
+}
+
+/// Hash of the 416 certified for an encoding, answering the ranges that start past its end
+pub fn range_not_satisfiable_response_hash(encoding: &AssetEncoding) -> Hash {
+    message_response_hash(416, &range_not_satisfiable_headers(encoding))
+}
+
+fn range_not_satisfiable_headers(encoding: &AssetEncoding) -> Vec<HeaderField> {
+    [
+        build_error_headers(),
+        vec![HeaderField(
+            "Content-Range".to_string(),
+            format!("bytes */{}", encoding.total_length),
+        )],
+    ]
+    .concat()
+}
+
+fn build_not_modified_response(
+    headers: Vec<HeaderField>,
+    certified_path: &CertifiedPath,
+    certificate_version: Option<u16>,
+) -> HttpResponse {
+    let headers = build_headers(
+        headers,
+        certified_path,
+        304,
+        &Sha256::digest([]).into(),
+        certificate_version,
+    );
+
+    match headers {
+        Err(error) => server_error_response(error),
+        Ok(headers) => HttpResponse {
+            body: Vec::new(),
+            headers,
+            status_code: 304,
+            streaming_strategy: None,
+        },
+    }
+}
+
+/// Conditional request of RFC 9110, If-None-Match taking precedence over If-Modified-Since
+fn is_not_modified(request_headers: &[HeaderField], encoding: &AssetEncoding) -> bool {
+    let entity_tags: Vec<&str> = request_headers
+        .iter()
+        .filter(|HeaderField(name, _)| name.eq_ignore_ascii_case("If-None-Match"))
+        .flat_map(|HeaderField(_, value)| value.split(','))
+        .map(str::trim)
+        .collect();
+
+    if !entity_tags.is_empty() {
+        let entity_tag = entity_tag(encoding);
+
+        // Weak comparison, as required for If-None-Match
+        return entity_tags
+            .iter()
+            .any(|tag| *tag == "*" || tag.trim_start_matches("W/") == entity_tag);
+    }
+
+    request_headers
+        .iter()
+        .find(|HeaderField(name, _)| name.eq_ignore_ascii_case("If-Modified-Since"))
+        .and_then(|HeaderField(_, value)| parse_http_date(value.trim()).ok())
+        .is_some_and(|since| last_modified(encoding) <= since)
+}
+
+fn build_certified_error_response(
+    status_code: u16,
+    certified_path: &CertifiedPath,
+    certificate_version: Option<u16>,
+) -> HttpResponse {
+    build_message_response(
+        status_code,
+        build_error_headers(),
+        certified_path,
+        certificate_version,
+    )
+}
+
+// Plain response, the message of its status code as body
+fn build_message_response(
+    status_code: u16,
+    headers: Vec<HeaderField>,
+    certified_path: &CertifiedPath,
+    certificate_version: Option<u16>,
+) -> HttpResponse {
+    let body = error_message(status_code).as_bytes();
+
+    let headers = build_headers(
+        headers,
+        certified_path,
+        status_code,
+        &Sha256::digest(body).into(),
+        certificate_version,
+    );
+
+    match headers {
+        Err(error) => server_error_response(error),
+        Ok(headers) => HttpResponse {
+            body: body.to_vec(),
+            headers,
+            status_code,
+            streaming_strategy: None,
+        },
+    }
+}
+
+/// Hash of the plain error response certified for the status code
+pub fn error_response_hash(status_code: u16) -> Hash {
+    message_response_hash(status_code, &build_error_headers())
+}
+
+fn message_response_hash(status_code: u16, headers: &[HeaderField]) -> Hash {
+    let body = error_message(status_code).as_bytes();
+    response_hash(headers, status_code, &Sha256::digest(body).into())
+}
+
+fn build_error_headers() -> Vec<HeaderField> {
+    vec![build_certificate_expression_header()]
+}
+
+fn error_message(status_code: u16) -> &'static str {
+    match status_code {
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        416 => "Range Not Satisfiable",
+        500 => "Internal Server Error",
+        503 => "Service Unavailable",
+        _ => "Error",
+    }
+}
+
+// Uncertified, a gateway that verifies the responses reports it as an error whatever the body
+fn server_error_response(error: ServerError) -> HttpResponse {
+    let status_code = match error {
+        ServerError::CertificateUnavailable => 503,
+        ServerError::CertificateEncoding | ServerError::ContentMissing => 500,
+    };
+
+    error_response(status_code, error_message(status_code))
+}
+
+// The assets are only read, a 405 lists the methods that are allowed
+pub fn method_not_allowed_response() -> HttpResponse {
+    HttpResponse {
+        headers: vec![HeaderField("Allow".to_string(), "GET".to_string())],
+        ..error_response(405, "Method Not Allowed")
+    }
+}
+
+fn error_response(status_code: u16, message: &str) -> HttpResponse {
+    HttpResponse {
+        body: message.as_bytes().to_vec(),
+        headers: Vec::new(),
+        status_code,
+        streaming_strategy: None,
+    }
+}
 
+fn streaming_strategy(
+    streaming_token: Option<StreamingCallbackToken>,
+) -> Option<StreamingStrategy> {
     streaming_token.map(|streaming_token| StreamingStrategy::Callback {
         callback: CallbackFunc::new(canister_self(), "http_request_streaming_callback".to_string()),
         token: streaming_token,
     })
 }
 
+/// The token of the request, not the id of the asset, is passed on to the next chunks so that the
+/// stream ends with the validity of the token
 pub fn create_token(
     key: &AssetKey,
-    chunk_index: usize,
+    token: Option<&String>,
+    encoding_type: &str,
+    remaining: Option<StreamingRange>,
     encoding: &AssetEncoding,
     headers: &[HeaderField],
 ) -> Option<StreamingCallbackToken> {
-    if chunk_index + 1 >= encoding.content_chunks.len() {
-        return None;
-    }
-
-    Some(StreamingCallbackToken {
+    remaining.map(|range| StreamingCallbackToken {
         full_path: key.full_path.clone(),
-        token: key.id.clone(),
+        encoding_type: encoding_type.to_string(),
+        token: token.cloned(),
         headers: headers.to_owned(),
-        index: chunk_index + 1,
         sha256: Some(ByteBuf::from(encoding.sha256)),
+        range,
+    })
+}
+
+/// Bytes of the content that follow the first `length` bytes already served, if any
+fn remaining_range(encoding: &AssetEncoding, length: usize) -> Option<StreamingRange> {
+    let offset = length as u128;
+
+    (offset < encoding.total_length).then(|| StreamingRange {
+        offset,
+        end: encoding.total_length - 1,
     })
 }
 
-pub fn build_headers(asset: &Asset) -> Result<Vec<HeaderField>, &'static str> {
-    let certified_header = build_certified_headers(asset);
+/// Bytes of the range held by the chunk in which the range starts, and the range that remains
+/// to be streamed after them.
+pub fn get_range_content(
+    encoding: &AssetEncoding,
+    StreamingRange { offset, end }: &StreamingRange,
+) -> Result<(Vec<u8>, Option<StreamingRange>), &'static str> {
+    let (index, chunk_start) = locate_chunk(encoding, *offset).ok_or("Range out of content.")?;
+    let chunk = get_content_chunk(encoding, index)?;
+
+    let chunk_end = chunk_start + chunk.len() as u128 - 1;
+    let from = (offset - chunk_start) as usize;
+    let to = (min(*end, chunk_end) - chunk_start) as usize;
+
+    let remaining = (*end > chunk_end).then_some(StreamingRange {
+        offset: chunk_end + 1,
+        end: *end,
+    });
+
+    Ok((chunk[from..=to].to_vec(), remaining))
+}
+
+/// Index of the chunk holding the byte at `offset` and the offset of its first byte
+fn locate_chunk(encoding: &AssetEncoding, offset: u128) -> Option<(usize, u128)> {
+    let mut chunk_start: u128 = 0;
+
+    for (index, length) in encoding.chunk_lengths.iter().enumerate() {
+        if offset < chunk_start + length {
+            return Some((index, chunk_start));
+        }
+
+        chunk_start += length;
+    }
+
+    None
+}
+
+/// Chunk that answers the single byte range of the Range header. Other units, multiple ranges,
+/// an invalid syntax or a range that starts within a chunk are served in full, as permitted by
+/// RFC 9110: only whole chunks are certified.
+fn requested_range(request_headers: &[HeaderField], encoding: &AssetEncoding) -> RequestedRange {
+    let range = request_headers
+        .iter()
+        .find(|HeaderField(name, _)| name.eq_ignore_ascii_case("Range"))
+        .and_then(|HeaderField(_, value)| value.trim().strip_prefix("bytes="))
+        .filter(|spec| !spec.contains(','))
+        .and_then(|spec| spec.split_once('-'));
+
+    let Some((start, end)) = range.map(|(start, end)| (start.trim(), end.trim())) else {
+        return RequestedRange::Full;
+    };
+
+    let total_length = encoding.total_length;
+
+    let offset = match (start.parse::<u128>(), end.parse::<u128>()) {
+        // bytes=-500 requests the last 500 bytes
+        (Err(_), Ok(suffix)) if start.is_empty() => {
+            if suffix == 0 {
+                return RequestedRange::Unsatisfiable;
+            }
+
+            total_length.saturating_sub(suffix)
+        }
+        (Ok(offset), Err(_)) if end.is_empty() => offset,
+        (Ok(offset), Ok(end)) if offset <= end => offset,
+        _ => return RequestedRange::Full,
+    };
+
+    if offset >= total_length {
+        return RequestedRange::Unsatisfiable;
+    }
+
+    match locate_chunk(encoding, offset) {
+        Some((index, chunk_start)) if chunk_start == offset => RequestedRange::Partial(index),
+        _ => RequestedRange::Full,
+    }
+}
+
+fn build_headers(
+    headers: Vec<HeaderField>,
+    certified_path: &CertifiedPath,
+    status_code: u16,
+    body_hash: &Hash,
+    certificate_version: Option<u16>,
+) -> Result<Vec<HeaderField>, ServerError> {
+    let response_hash = response_hash(&headers, status_code, body_hash);
+
+    let certified_header =
+        build_certified_headers(certified_path, &response_hash, certificate_version);
 
     match certified_header {
         Err(err) => Err(err),
-        Ok(certified_header) => {
-            let mut headers = asset.headers.clone();
-            headers.push(certified_header);
-            Ok([headers, security_headers()].concat())
+        Ok(certified_header) => Ok([headers, vec![certified_header]].concat()),
+    }
+}
+
+/// Headers of the response for an encoding of the asset, all of them certified
+pub fn build_asset_headers(
+    asset: &Asset,
+    encoding_type: &str,
+    rules: &[CacheControlRule],
+) -> Vec<HeaderField> {
+    let encoding = asset
+        .encodings
+        .get(encoding_type)
+        .unwrap_or_else(|| asset.encoding_raw());
+
+    let mut headers = [asset.headers.clone(), cache_control_headers(asset, rules)].concat();
+    headers.push(HeaderField(
+        "Accept-Ranges".to_string(),
+        "bytes".to_string(),
+    ));
+    headers.push(build_certificate_expression_header());
+
+    [
+        headers,
+        validator_headers(encoding),
+        encoding_headers(encoding_type),
+        security_headers(),
+    ]
+    .concat()
+}
+
+// The Cache-Control of the asset, if any, takes precedence over the rules of the bucket
+fn cache_control_headers(asset: &Asset, rules: &[CacheControlRule]) -> Vec<HeaderField> {
+    let has_cache_control = asset
+        .headers
+        .iter()
+        .any(|HeaderField(name, _)| name.eq_ignore_ascii_case("Cache-Control"));
+
+    if has_cache_control {
+        return Vec::new();
+    }
+
+    rules
+        .iter()
+        .find(|rule| rule.matches(&asset.key))
+        .map(|rule| HeaderField("Cache-Control".to_string(), rule.cache_control.clone()))
+        .into_iter()
+        .collect()
+}
+
+fn validator_headers(encoding: &AssetEncoding) -> Vec<HeaderField> {
+    vec![
+        HeaderField("ETag".to_string(), entity_tag(encoding)),
+        HeaderField(
+            "Last-Modified".to_string(),
+            fmt_http_date(last_modified(encoding)),
+        ),
+    ]
+}
+
+// Strong validator, each encoding having its own hash
+fn entity_tag(encoding: &AssetEncoding) -> String {
+    format!("\"{}\"", hex::encode(encoding.sha256))
+}
+
+// HTTP dates have a precision of a second
+fn last_modified(encoding: &AssetEncoding) -> SystemTime {
+    UNIX_EPOCH + Duration::from_secs(encoding.modified / 1_000_000_000)
+}
+
+fn encoding_headers(encoding_type: &str) -> Vec<HeaderField> {
+    // The response depends on the Accept-Encoding of the request even when served raw
+    let mut headers = vec![HeaderField(
+        "Vary".to_string(),
+        "Accept-Encoding".to_string(),
+    )];
+
+    if encoding_type != ASSET_ENCODING_KEY_RAW {
+        headers.push(HeaderField(
+            "Content-Encoding".to_string(),
+            encoding_type.to_string(),
+        ));
+    }
+
+    headers
+}
+
+/// Picks the encoded variant of the asset with the highest quality in the Accept-Encoding header
+/// of the request, ties resolved by `ASSET_ENCODING_KEYS_PREFERENCE`. Falls back to raw.
+fn select_encoding_type(
+    asset: &Asset,
+    request_headers: &[HeaderField],
+    certificate_version: Option<u16>,
+) -> String {
+    let accepted = accepted_encodings(request_headers);
+
+    // A gateway that verifies the v1 certification cannot decode br to compare it with the raw
+    let verifies_v2 = certificate_version.is_some_and(|version| version >= 2);
+
+    let mut selected: Option<(&str, f32)> = None;
+
+    for encoding_type in ASSET_ENCODING_KEYS_PREFERENCE {
+        if !asset.encodings.contains_key(encoding_type)
+            || (!verifies_v2 && encoding_type == ASSET_ENCODING_KEY_BROTLI)
+        {
+            continue;
+        }
+
+        match accepted_quality(&accepted, encoding_type) {
+            Some(quality)
+                if quality > 0.0
+                    && selected.is_none_or(|(_, selected_quality)| quality > selected_quality) =>
+            {
+                selected = Some((encoding_type, quality));
+            }
+            _ => (),
         }
     }
+
+    selected.map_or_else(
+        || ASSET_ENCODING_KEY_RAW.to_string(),
+        |(encoding_type, _)| encoding_type.to_string(),
+    )
 }
 
-fn build_certified_headers(asset: &Asset) -> Result<HeaderField, &'static str> {
-    STATE.with(|state| build_certified_headers_impl(asset, &state.borrow().runtime))
+fn accepted_encodings(request_headers: &[HeaderField]) -> Vec<(String, f32)> {
+    request_headers
+        .iter()
+        .filter(|HeaderField(name, _)| name.eq_ignore_ascii_case("Accept-Encoding"))
+        .flat_map(|HeaderField(_, value)| value.split(','))
+        .filter_map(|item| {
+            let mut params = item.split(';');
+            let name = params.next()?.trim().to_ascii_lowercase();
+
+            // A quality that cannot be parsed discards the item
+            let quality = match params.find_map(|param| param.trim().strip_prefix("q=")) {
+                None => 1.0,
+                Some(quality) => quality.trim().parse::<f32>().ok()?,
+            };
+
+            (!name.is_empty()).then_some((name, quality))
+        })
+        .collect()
+}
+
+fn accepted_quality(accepted: &[(String, f32)], encoding_type: &str) -> Option<f32> {
+    accepted
+        .iter()
+        .find(|(name, _)| name == encoding_type)
+        .or_else(|| accepted.iter().find(|(name, _)| name == "*"))
+        .map(|(_, quality)| *quality)
+}
+
+fn build_certified_headers(
+    certified_path: &CertifiedPath,
+    response_hash: &Hash,
+    certificate_version: Option<u16>,
+) -> Result<HeaderField, ServerError> {
+    STATE.with(|state| {
+        build_certified_headers_impl(
+            certified_path,
+            response_hash,
+            certificate_version,
+            &state.borrow().runtime,
+        )
+    })
 }
 
 fn build_certified_headers_impl(
-    Asset { key, .. }: &Asset,
+    certified_path: &CertifiedPath,
+    response_hash: &Hash,
+    certificate_version: Option<u16>,
     state: &RuntimeState,
-) -> Result<HeaderField, &'static str> {
-    build_asset_certificate_header(&state.asset_hashes, &key.full_path)
+) -> Result<HeaderField, ServerError> {
+    build_asset_certificate_header(
+        &state.asset_hashes,
+        certified_path,
+        response_hash,
+        certificate_version,
+    )
+}
+
+/// Path of the requested url, as certified: decoded and normalized, without the query
+fn certified_url(url: &str) -> String {
+    match parse_url(url) {
+        Ok(RequestUrl { path, .. }) => path,
+        // The 400 of an invalid url is certified under the fallback, whatever the path
+        Err(_) => "/".to_string(),
+    }
 }
 
 // Source: NNS-dapp
@@ -83,3 +752,252 @@ fn security_headers() -> Vec<HeaderField> {
         HeaderField("Referrer-Policy".to_string(), "same-origin".to_string()),
     ]
 }
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        accepted_encodings, partial_content_headers, requested_range, select_encoding_type,
+        RequestedRange,
+    };
+    use crate::types::http::HeaderField;
+    use crate::types::store::{Asset, AssetEncoding, AssetKey};
+    use std::collections::HashMap;
+
+    fn accept_encoding(value: &str) -> Vec<HeaderField> {
+        vec![HeaderField(
+            "accept-encoding".to_string(),
+            value.to_string(),
+        )]
+    }
+
+    fn encoding(chunk_lengths: &[u128]) -> AssetEncoding {
+        AssetEncoding {
+            modified: 0,
+            content_chunks: (0..chunk_lengths.len() as u64).collect(),
+            chunk_lengths: chunk_lengths.to_vec(),
+            chunk_sha256s: vec![[0; 32]; chunk_lengths.len()],
+            total_length: chunk_lengths.iter().sum(),
+            sha256: [0; 32],
+            uploaded_by: None,
+        }
+    }
+
+    fn asset(encoding_types: &[&str]) -> Asset {
+        let encoding = encoding(&[]);
+
+        Asset {
+            key: AssetKey {
+                name: "index.html".to_string(),
+                created: 0,
+                folder: "/".to_string(),
+                full_path: "/index.html".to_string(),
+                id: None,
+                size: 0,
+                preview: None,
+            },
+            headers: Vec::new(),
+            encodings: encoding_types
+                .iter()
+                .map(|encoding_type| (encoding_type.to_string(), encoding.clone()))
+                .collect::<HashMap<_, _>>(),
+            commit: None,
+        }
+    }
+
+    fn select(encoding_types: &[&str], accept: &str) -> String {
+        select_encoding_type(&asset(encoding_types), &accept_encoding(accept), Some(2))
+    }
+
+    #[test]
+    fn parses_the_qualities() {
+        assert_eq!(
+            accepted_encodings(&accept_encoding("gzip, BR;q=0.5 , deflate; q=0")),
+            vec![
+                ("gzip".to_string(), 1.0),
+                ("br".to_string(), 0.5),
+                ("deflate".to_string(), 0.0)
+            ]
+        );
+    }
+
+    #[test]
+    fn discards_the_items_with_an_invalid_quality() {
+        assert_eq!(
+            accepted_encodings(&accept_encoding("gzip;q=high, br;q=0.8, , ;q=1")),
+            vec![("br".to_string(), 0.8)]
+        );
+    }
+
+    #[test]
+    fn reads_every_accept_encoding_header() {
+        let headers = vec![
+            HeaderField("Accept-Encoding".to_string(), "gzip".to_string()),
+            HeaderField("Accept".to_string(), "text/html".to_string()),
+            HeaderField("accept-encoding".to_string(), "br".to_string()),
+        ];
+
+        assert_eq!(
+            accepted_encodings(&headers),
+            vec![("gzip".to_string(), 1.0), ("br".to_string(), 1.0)]
+        );
+    }
+
+    #[test]
+    fn selects_the_highest_quality() {
+        assert_eq!(select(&["raw", "gzip", "br"], "gzip;q=1, br;q=0.5"), "gzip");
+        assert_eq!(select(&["raw", "gzip", "br"], "gzip;q=0.5, br;q=0.9"), "br");
+    }
+
+    #[test]
+    fn breaks_ties_with_the_preference() {
+        assert_eq!(
+            select(&["raw", "gzip", "br", "deflate"], "deflate, gzip, br"),
+            "br"
+        );
+        assert_eq!(select(&["raw", "gzip", "deflate"], "deflate, gzip"), "gzip");
+    }
+
+    #[test]
+    fn serves_the_raw_content_when_no_variant_is_accepted() {
+        assert_eq!(select(&["raw", "gzip"], "gzip;q=0"), "raw");
+        assert_eq!(select(&["raw", "gzip"], "identity"), "raw");
+        assert_eq!(select(&["raw", "br"], "gzip"), "raw");
+        assert_eq!(select(&["raw", "gzip"], ""), "raw");
+    }
+
+    #[test]
+    fn applies_the_wildcard_to_the_unlisted_variants() {
+        assert_eq!(select(&["raw", "gzip", "br"], "*"), "br");
+        assert_eq!(select(&["raw", "gzip", "br"], "br;q=0, *;q=0.5"), "gzip");
+        assert_eq!(select(&["raw", "gzip"], "*;q=0"), "raw");
+    }
+
+    #[test]
+    fn serves_no_br_to_the_gateways_verifying_v1() {
+        let asset = asset(&["raw", "gzip", "br"]);
+
+        for certificate_version in [None, Some(1)] {
+            assert_eq!(
+                select_encoding_type(
+                    &asset,
+                    &accept_encoding("br, gzip;q=0.5"),
+                    certificate_version
+                ),
+                "gzip"
+            );
+            assert_eq!(
+                select_encoding_type(&asset, &accept_encoding("br"), certificate_version),
+                "raw"
+            );
+        }
+    }
+
+    fn range(value: &str) -> Vec<HeaderField> {
+        vec![HeaderField("Range".to_string(), value.to_string())]
+    }
+
+    #[test]
+    fn serves_the_chunk_a_range_starts_with() {
+        let encoding = encoding(&[100, 100, 50]);
+
+        assert_eq!(
+            requested_range(&range("bytes=0-"), &encoding),
+            RequestedRange::Partial(0)
+        );
+        assert_eq!(
+            requested_range(&range("bytes=100-149"), &encoding),
+            RequestedRange::Partial(1)
+        );
+        assert_eq!(
+            requested_range(&range(" bytes=200-999 "), &encoding),
+            RequestedRange::Partial(2)
+        );
+    }
+
+    #[test]
+    fn serves_a_suffix_range_from_the_chunk_it_starts_with() {
+        let encoding = encoding(&[100, 100, 50]);
+
+        assert_eq!(
+            requested_range(&range("bytes=-50"), &encoding),
+            RequestedRange::Partial(2)
+        );
+        assert_eq!(
+            requested_range(&range("bytes=-1000"), &encoding),
+            RequestedRange::Partial(0)
+        );
+    }
+
+    #[test]
+    fn serves_in_full_the_ranges_that_start_within_a_chunk() {
+        let encoding = encoding(&[100, 100, 50]);
+
+        assert_eq!(
+            requested_range(&range("bytes=150-"), &encoding),
+            RequestedRange::Full
+        );
+        assert_eq!(
+            requested_range(&range("bytes=-10"), &encoding),
+            RequestedRange::Full
+        );
+    }
+
+    #[test]
+    fn serves_in_full_the_ranges_it_does_not_support() {
+        let encoding = encoding(&[100, 100, 50]);
+
+        for value in [
+            "bytes=0-99, 200-249",
+            "items=0-1",
+            "bytes=100-0",
+            "bytes=a-b",
+            "bytes=-",
+            "bytes=100",
+        ] {
+            assert_eq!(
+                requested_range(&range(value), &encoding),
+                RequestedRange::Full,
+                "{value}"
+            );
+        }
+
+        assert_eq!(requested_range(&[], &encoding), RequestedRange::Full);
+    }
+
+    #[test]
+    fn answers_the_ranges_past_the_end_as_unsatisfiable() {
+        let encoding = encoding(&[100, 100, 50]);
+
+        assert_eq!(
+            requested_range(&range("bytes=250-"), &encoding),
+            RequestedRange::Unsatisfiable
+        );
+        assert_eq!(
+            requested_range(&range("bytes=-0"), &encoding),
+            RequestedRange::Unsatisfiable
+        );
+    }
+
+    #[test]
+    fn answers_any_range_of_an_empty_content_as_unsatisfiable() {
+        let encoding = encoding(&[0]);
+
+        assert_eq!(
+            requested_range(&range("bytes=0-"), &encoding),
+            RequestedRange::Unsatisfiable
+        );
+        assert_eq!(
+            requested_range(&range("bytes=-10"), &encoding),
+            RequestedRange::Unsatisfiable
+        );
+    }
+
+    #[test]
+    fn describes_the_chunk_served_as_partial_content() {
+        let headers = partial_content_headers(Vec::new(), &encoding(&[100, 100, 50]), 2);
+
+        assert_eq!(headers.len(), 1);
+        assert_eq!(headers[0].0, "Content-Range");
+        assert_eq!(headers[0].1, "bytes 200-249/250");
+    }
+}
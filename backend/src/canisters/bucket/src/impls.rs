@@ -1,33 +1,220 @@
+use candid::{Decode, Encode, Principal};
 use ic_cdk::api::time;
+use ic_certified_map::{leaf_hash, AsHashTree, Hash, HashTree, RbTree};
+use ic_stable_structures::storable::Bound;
+use ic_stable_structures::Storable;
 use sha2::{Digest, Sha256};
+use std::borrow::Cow;
+use std::cmp::Ordering;
 use std::fmt;
 
-use crate::types::assets::AssetHashes;
-use crate::types::state::Assets;
-use crate::types::store::{Asset, AssetEncoding};
+use crate::cert::{
+    delete_nested, expr_labels, expr_path, fallback_expr_path, insert_nested, response_hash,
+    response_path,
+};
+use crate::http::{
+    build_asset_headers, error_response_hash, partial_content_response_hashes,
+    range_not_satisfiable_response_hash,
+};
+use crate::memory::init_stable_state;
+use crate::routing::{alias_urls, resolve_url};
+use crate::types::assets::{AssetHashes, NestedTree, NestedTreeNode};
+use crate::types::interface::{BucketInitArgs, ListCursor, ListOrder};
+use crate::types::state::{
+    Assets, CacheControlPattern, CacheControlRule, HeapState, Role, RoutingConfig, StableState,
+    StorageUsage,
+};
+use crate::types::store::{Asset, AssetEncoding, AssetKey, AssetRevisions, GetAssetError};
 
 pub static ASSET_ENCODING_KEY_RAW: &str = "raw";
+pub static ASSET_ENCODING_KEY_GZIP: &str = "gzip";
+pub static ASSET_ENCODING_KEY_DEFLATE: &str = "deflate";
+pub static ASSET_ENCODING_KEY_BROTLI: &str = "br";
 
-impl From<&Assets> for AssetHashes {
-    fn from(assets: &Assets) -> Self {
+/// Encoded variants in order of preference when a client accepts several of them equally. The
+/// keys are the `Content-Encoding` names of the variants.
+pub static ASSET_ENCODING_KEYS_PREFERENCE: [&str; 3] = [
+    ASSET_ENCODING_KEY_BROTLI,
+    ASSET_ENCODING_KEY_GZIP,
+    ASSET_ENCODING_KEY_DEFLATE,
+];
+
+impl Default for AssetHashes {
+    fn default() -> Self {
+        Self {
+            tree: RbTree::new(),
+            expr_tree: NestedTree::new(),
+        }
+    }
+}
+
+impl AssetHashes {
+    pub(crate) fn from_assets(
+        assets: &Assets,
+        routing: &RoutingConfig,
+        rules: &[CacheControlRule],
+    ) -> Self {
         let mut asset_hashes = Self::default();
 
         for asset in assets.values() {
-            asset_hashes.insert(asset);
+            for url in alias_urls(&asset.key.full_path, routing) {
+                // An url is certified once, with the asset it resolves to
+                let resolved = resolve_url(&url, routing, assets);
+
+                if resolved.is_some_and(|resolved| resolved.key.full_path == asset.key.full_path) {
+                    asset_hashes.insert(&url, &asset, rules);
+                }
+            }
         }
 
         asset_hashes
     }
-}
 
-impl AssetHashes {
-    pub(crate) fn insert(&mut self, asset: &Asset) {
+    /// Certifies the responses of the asset served for the url
+    pub(crate) fn insert(&mut self, url: &str, asset: &Asset, rules: &[CacheControlRule]) {
+        // v1 - The hash of the raw content certifies the gzip and deflate variants as well, the
+        // gateways that verify v1 decode these before comparing the body with the certified hash.
+        // They do not decode br, which is never served to them.
         self.tree
-            .insert(asset.key.full_path.clone(), asset.encoding_raw().sha256);
+            .insert(url.to_string(), asset.encoding_raw().sha256);
+
+        // v2 - Each encoding is served with its own headers, hence certified as a response,
+        // as the 304 of its revalidation and as the partial contents of the Range requests
+        let mut response_hashes = [
+            asset_response_hashes(asset, 200, rules),
+            asset_response_hashes(asset, 304, rules),
+            asset_range_response_hashes(asset, rules),
+        ]
+        .concat();
+
+        // The url of a protected asset is answered with a 403 when the token is invalid
+        if asset.key.id.is_some() {
+            response_hashes.push(error_response_hash(403));
+        }
+
+        self.insert_responses(&expr_labels(&expr_path(url)), response_hashes);
     }
 
-    pub(crate) fn delete(&mut self, full_path: &String) {
-        self.tree.delete(full_path.as_bytes());
+    pub(crate) fn delete(&mut self, url: &str) {
+        self.tree.delete(url.as_bytes());
+        delete_nested(&mut self.expr_tree, &expr_labels(&expr_path(url)));
+    }
+
+    /// Certifies the responses to the urls that match no asset: the fallback asset, or a plain
+    /// 404 if there is none, and the 400 of an invalid url.
+    pub(crate) fn insert_fallback(
+        &mut self,
+        fallback: Option<&Asset>,
+        status_code: u16,
+        rules: &[CacheControlRule],
+    ) {
+        let mut response_hashes = match fallback {
+            None => vec![error_response_hash(404)],
+            Some(asset) => asset_response_hashes(asset, status_code, rules),
+        };
+
+        response_hashes.push(error_response_hash(400));
+
+        self.insert_responses(&expr_labels(&fallback_expr_path()), response_hashes);
+    }
+
+    // Replaces the responses certified under the path of the v2 tree
+    fn insert_responses(&mut self, path: &[Vec<u8>], response_hashes: Vec<Hash>) {
+        delete_nested(&mut self.expr_tree, path);
+
+        for response_hash in response_hashes {
+            let response_path = [path.to_vec(), response_path(&response_hash)].concat();
+            insert_nested(&mut self.expr_tree, &response_path, Vec::new());
+        }
+    }
+}
+
+fn asset_response_hashes(asset: &Asset, status_code: u16, rules: &[CacheControlRule]) -> Vec<Hash> {
+    asset
+        .encodings
+        .iter()
+        .map(|(encoding_type, encoding)| {
+            let headers = build_asset_headers(asset, encoding_type, rules);
+
+            let body_hash = match status_code {
+                304 => Sha256::digest([]).into(),
+                _ => encoding.sha256,
+            };
+
+            response_hash(&headers, status_code, &body_hash)
+        })
+        .collect()
+}
+
+// Each chunk of an encoding answers the ranges that start with it, a 416 those past its end
+fn asset_range_response_hashes(asset: &Asset, rules: &[CacheControlRule]) -> Vec<Hash> {
+    asset
+        .encodings
+        .iter()
+        .flat_map(|(encoding_type, encoding)| {
+            let headers = build_asset_headers(asset, encoding_type, rules);
+
+            [
+                partial_content_response_hashes(&headers, encoding),
+                vec![range_not_satisfiable_response_hash(encoding)],
+            ]
+            .concat()
+        })
+        .collect()
+}
+
+impl AsHashTree for NestedTreeNode {
+    fn root_hash(&self) -> Hash {
+        match self {
+            NestedTreeNode::Leaf(value) => leaf_hash(value),
+            NestedTreeNode::Nested(tree) => tree.root_hash(),
+        }
+    }
+
+    fn as_hash_tree(&self) -> HashTree<'_> {
+        match self {
+            NestedTreeNode::Leaf(value) => value.as_hash_tree(),
+            NestedTreeNode::Nested(tree) => tree.as_hash_tree(),
+        }
+    }
+}
+
+impl CacheControlRule {
+    pub(crate) fn matches(&self, key: &AssetKey) -> bool {
+        match &self.pattern {
+            CacheControlPattern::Folder(folder) => &key.folder == folder,
+            CacheControlPattern::Glob(glob) => {
+                glob_matches(glob.as_bytes(), key.full_path.as_bytes())
+            }
+        }
+    }
+}
+
+fn glob_matches(glob: &[u8], path: &[u8]) -> bool {
+    match glob {
+        [] => path.is_empty(),
+        [b'*', b'*', rest @ ..] => (0..=path.len()).any(|start| glob_matches(rest, &path[start..])),
+        [b'*', rest @ ..] => (0..=path.len())
+            .take_while(|start| *start == 0 || path[start - 1] != b'/')
+            .any(|start| glob_matches(rest, &path[start..])),
+        [b'?', rest @ ..] => match path {
+            [first, path @ ..] if *first != b'/' => glob_matches(rest, path),
+            _ => false,
+        },
+        [expected, rest @ ..] => match path {
+            [first, path @ ..] if first == expected => glob_matches(rest, path),
+            _ => false,
+        },
+    }
+}
+
+impl From<GetAssetError> for &'static str {
+    fn from(error: GetAssetError) -> Self {
+        match error {
+            GetAssetError::BadRequest(message)
+            | GetAssetError::Forbidden(message)
+            | GetAssetError::NotFound(message) => message,
+        }
     }
 }
 
@@ -44,16 +231,23 @@ impl fmt::Display for AssetEncodingError {
     }
 }
 
+/// The content is only hashed and measured, the ids of the chunks are assigned once the chunks
+/// are written in stable memory.
 impl TryFrom<&Vec<Vec<u8>>> for AssetEncoding {
     type Error = AssetEncodingError;
 
     fn try_from(content_chunks: &Vec<Vec<u8>>) -> Result<Self, Self::Error> {
         let mut total_length: u128 = 0;
+        let mut chunk_lengths: Vec<u128> = Vec::with_capacity(content_chunks.len());
+        let mut chunk_sha256s: Vec<Hash> = Vec::with_capacity(content_chunks.len());
         let mut hasher = Sha256::new();
 
         for chunk in content_chunks {
             match u128::try_from(chunk.len()) {
-                Ok(len) => total_length += len,
+                Ok(len) => {
+                    total_length += len;
+                    chunk_lengths.push(len);
+                }
                 Err(_) => {
                     return Err(AssetEncodingError {
                         description: "Failed to convert chunk length to u128".to_string(),
@@ -62,15 +256,19 @@ impl TryFrom<&Vec<Vec<u8>>> for AssetEncoding {
             }
 
             hasher.update(chunk);
+            chunk_sha256s.push(Sha256::digest(chunk).into());
         }
 
         let sha256 = hasher.finalize().into();
 
         Ok(Self {
             modified: time(), // Replace with the actual function that returns time
-            content_chunks: content_chunks.clone(),
+            content_chunks: Vec::new(),
+            chunk_lengths,
+            chunk_sha256s,
             total_length,
             sha256,
+            uploaded_by: None,
         })
     }
 }
@@ -80,3 +278,167 @@ impl Asset {
         self.encodings.get(ASSET_ENCODING_KEY_RAW).unwrap()
     }
 }
+
+impl From<&AssetKey> for ListCursor {
+    fn from(key: &AssetKey) -> Self {
+        ListCursor {
+            full_path: key.full_path.clone(),
+            created: key.created,
+            size: key.size,
+            name: key.name.clone(),
+        }
+    }
+}
+
+impl ListOrder {
+    pub fn compare(&self, lhs: &ListCursor, rhs: &ListCursor) -> Ordering {
+        let ordering = match self {
+            ListOrder::FullPath => Ordering::Equal,
+            ListOrder::Created => lhs.created.cmp(&rhs.created),
+            ListOrder::Size => lhs.size.cmp(&rhs.size),
+            ListOrder::Name => lhs.name.cmp(&rhs.name),
+        };
+
+        ordering.then_with(|| lhs.full_path.cmp(&rhs.full_path))
+    }
+}
+
+impl HeapState {
+    pub(crate) fn apply_init_args(
+        &mut self,
+        BucketInitArgs {
+            owner,
+            admins,
+            quotas,
+        }: BucketInitArgs,
+    ) -> Result<(), &'static str> {
+        if owner == Principal::anonymous() {
+            return Err("The owner of the bucket cannot be the anonymous principal.");
+        }
+
+        // The owner is the user of the bucket, it does not need an explicit permission
+        self.permissions.remove(&owner);
+        self.user = Some(owner);
+
+        for admin in admins.into_iter().filter(|admin| *admin != owner) {
+            self.permissions.insert(admin, Role::Owner);
+        }
+
+        if let Some(quotas) = quotas {
+            self.quotas = quotas;
+        }
+
+        Ok(())
+    }
+}
+
+impl StorageUsage {
+    pub fn add(&mut self, uploader: Option<Principal>, size: u128) {
+        self.bucket_size = self.bucket_size.saturating_add(size);
+
+        if let Some(uploader) = uploader {
+            let uploader_size = self.uploaders.entry(uploader).or_default();
+            *uploader_size = uploader_size.saturating_add(size);
+        }
+    }
+
+    pub fn remove(&mut self, uploader: Option<Principal>, size: u128) {
+        self.bucket_size = self.bucket_size.saturating_sub(size);
+
+        if let Some(uploader) = uploader {
+            if let Some(uploader_size) = self.uploaders.get_mut(&uploader) {
+                *uploader_size = uploader_size.saturating_sub(size);
+
+                if *uploader_size == 0 {
+                    self.uploaders.remove(&uploader);
+                }
+            }
+        }
+    }
+
+    pub fn uploader_size(&self, uploader: &Principal) -> u128 {
+        self.uploaders.get(uploader).copied().unwrap_or_default()
+    }
+}
+
+impl Default for StableState {
+    fn default() -> Self {
+        init_stable_state()
+    }
+}
+
+impl Storable for Asset {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for AssetRevisions {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+impl Storable for HeapState {
+    fn to_bytes(&self) -> Cow<'_, [u8]> {
+        Cow::Owned(Encode!(self).unwrap())
+    }
+
+    fn from_bytes(bytes: Cow<[u8]>) -> Self {
+        Decode!(bytes.as_ref(), Self).unwrap()
+    }
+
+    const BOUND: Bound = Bound::Unbounded;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_matches;
+
+    fn matches(glob: &str, path: &str) -> bool {
+        glob_matches(glob.as_bytes(), path.as_bytes())
+    }
+
+    #[test]
+    fn star_stays_within_a_segment() {
+        assert!(matches("/images/*", "/images/logo.png"));
+        assert!(matches("/images/*.png", "/images/logo.png"));
+        assert!(matches("/images/*", "/images/"));
+        assert!(!matches("/images/*", "/images/icons/logo.png"));
+        assert!(!matches("/*.png", "/images/logo.png"));
+    }
+
+    #[test]
+    fn double_star_crosses_segments() {
+        assert!(matches("/images/**", "/images/icons/logo.png"));
+        assert!(matches("/**.png", "/images/icons/logo.png"));
+        assert!(matches("/**/logo.png", "/images/icons/logo.png"));
+        assert!(!matches("/**.png", "/images/logo.jpg"));
+    }
+
+    #[test]
+    fn question_mark_matches_one_character_but_slash() {
+        assert!(matches("/logo.???", "/logo.png"));
+        assert!(!matches("/logo.???", "/logo.webp"));
+        assert!(!matches("/a?b", "/a/b"));
+    }
+
+    #[test]
+    fn literals_match_the_whole_path() {
+        assert!(matches("/index.html", "/index.html"));
+        assert!(!matches("/index.html", "/index.html.gz"));
+        assert!(!matches("/index", "/index.html"));
+    }
+}
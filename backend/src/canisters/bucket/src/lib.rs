@@ -1,71 +1,123 @@
 mod cert;
+mod guards;
 mod http;
 mod impls;
+mod memory;
+mod migration;
+mod mime;
+mod routing;
 mod store;
+mod token;
 mod types;
+mod url;
 
-use crate::cert::update_certified_data;
-use crate::http::{build_headers, create_token, streaming_strategy};
+use crate::guards::{caller_can_read, caller_can_upload, caller_is_owner};
+use crate::http::{
+    build_asset_response, build_error_response, create_token, get_range_content,
+    method_not_allowed_response,
+};
+use crate::memory::{init_stable_state, restore_heap_state, save_heap_state};
+use crate::migration::{migrate_legacy_state, restore_legacy_state};
 use crate::types::assets::AssetHashes;
 use crate::types::http::{
     HttpRequest, HttpResponse, StreamingCallbackHttpResponse, StreamingCallbackToken,
 };
-use crate::types::interface::{CommitBatch, Del, InitUpload, UploadChunk};
-use crate::types::state::{RuntimeState, StableState, State};
+use crate::types::interface::{
+    AccessToken, AssetRevision, BatchChunks, BucketError, BucketInitArgs, CommitBatch,
+    CreateAccessToken, Del, FolderListing, FolderStats, GcStats, GetRevisionChunk, InitDeploy,
+    InitUpload, ListParams, ListResults, PendingBatch, Permission, UploadChunk, Usage,
+};
+use crate::types::state::{
+    CacheControlRule, HeapState, Quotas, RoutingConfig, RuntimeState, State, SweepStats,
+};
 use crate::types::store::{AssetKey, Chunk};
 use candid::Principal;
 use ic_cdk::api::{canister_cycle_balance, msg_caller, trap};
 use ic_cdk::export_candid;
-use ic_cdk::storage::{stable_restore, stable_save};
+use ic_cdk::management_canister::raw_rand;
 use ic_cdk::{init, post_upgrade, pre_upgrade, query, update};
-use std::{cell::RefCell, collections::HashMap};
+use ic_cdk_timers::set_timer_interval;
+use serde_bytes::ByteBuf;
+use std::cell::RefCell;
+use std::collections::{BTreeSet, HashMap};
+use std::time::Duration;
 use store::{get_asset, get_asset_for_url, get_len};
-use types::store::Asset;
 
-use crate::store::{commit_batch, create_batch, create_chunk, delete_asset, get_keys};
+use crate::store::{
+    commit_batch, create_batch, create_chunk, create_deploy, delete_asset, get_batch_chunks,
+    get_cache_control_rules, get_fallback, get_folder_stats, get_gc_stats, get_keys,
+    get_pending_batches, get_permissions, get_revision_chunk, get_revisions, get_routing,
+    get_usage, grant_permission, has_batch_secret, has_token_secret, init_batch_secret,
+    init_certified_assets, init_token_secret, list_keys, revoke_permission, rollback_asset,
+    set_cache_control_rules, set_fallback, stage_batch, stage_deletion, sweep_expired_batches,
+};
 
 thread_local! {
   static STATE: RefCell<State> = RefCell::default();
 }
 
 #[init]
-fn init() {
+fn init(args: BucketInitArgs) {
+    let mut heap = HeapState::default();
+
+    if let Err(error) = heap.apply_init_args(args) {
+        trap(error);
+    }
+
     STATE.with(|state| {
-        *state.borrow_mut() = State {
-            stable: StableState { user: None, assets: HashMap::new() },
+        let state = &mut *state.borrow_mut();
+
+        *state = State {
+            stable: init_stable_state(),
+            heap,
             runtime: RuntimeState {
                 chunks: HashMap::new(),
                 batches: HashMap::new(),
                 asset_hashes: AssetHashes::default(),
+                batch_expiries: BTreeSet::new(),
+                sweep_stats: SweepStats::default(),
             },
         };
+
+        // Certify the responses to the urls, all of them missing
+        init_certified_assets(state);
+    });
+
+    set_timer_interval(BATCHES_SWEEP_INTERVAL, || {
+        sweep_expired_batches();
     });
 }
 
 #[pre_upgrade]
 fn pre_upgrade() {
-    STATE.with(|state| stable_save((&state.borrow().stable,)).unwrap());
+    STATE.with(|state| save_heap_state(&state.borrow().heap));
 }
 
 #[post_upgrade]
-fn post_upgrade() {
-    let (stable,): (StableState,) = stable_restore().unwrap();
+fn post_upgrade(args: Option<BucketInitArgs>) {
+    // Must be read before the memory manager claims the stable memory
+    let legacy = restore_legacy_state();
 
-    let asset_hashes = AssetHashes::from(&stable.assets);
-
-    // Populate state
     STATE.with(|state| {
-        *state.borrow_mut() = State {
-            stable,
-            runtime: RuntimeState {
-                chunks: HashMap::new(),
-                batches: HashMap::new(),
-                asset_hashes: asset_hashes.clone(),
-            },
+        let state = &mut *state.borrow_mut();
+
+        match legacy {
+            Some(legacy) => migrate_legacy_state(legacy, state),
+            None => state.heap = restore_heap_state(),
         }
+
+        if let Some(Err(error)) = args.map(|args| state.heap.apply_init_args(args)) {
+            trap(error);
+        }
+
+        // Populate the certified tree from the assets in stable memory
+        init_certified_assets(state);
     });
 
-    update_certified_data(&asset_hashes);
+    // Timers are not kept over upgrades
+    set_timer_interval(BATCHES_SWEEP_INTERVAL, || {
+        sweep_expired_batches();
+    });
 }
 
 //
@@ -73,131 +125,136 @@ fn post_upgrade() {
 //
 
 #[query]
-fn http_request(HttpRequest { method, url, .. }: HttpRequest) -> HttpResponse {
+fn http_request(
+    HttpRequest {
+        method,
+        url,
+        headers: request_headers,
+        certificate_version,
+        ..
+    }: HttpRequest,
+) -> HttpResponse {
     if method != "GET" {
-        return HttpResponse {
-            body: b"Method Not Allowed".to_vec(),
-            headers: Vec::new(),
-            status_code: 405,
-            streaming_strategy: None,
-        };
+        return method_not_allowed_response();
     }
 
     let result = get_asset_for_url(&url);
 
     match result {
-        Ok(asset) => {
-            let headers = build_headers(&asset);
-
-            let encoding = asset.encoding_raw();
-            let Asset { key, .. } = &asset;
-
-            match headers {
-                Ok(headers) => HttpResponse {
-                    body: encoding.content_chunks[0].clone(),
-                    headers: headers.clone(),
-                    status_code: 200,
-                    streaming_strategy: streaming_strategy(key, encoding, &headers),
-                },
-                Err(err) => HttpResponse {
-                    body: ["Permission denied. Invalid headers. ", err]
-                        .join("")
-                        .as_bytes()
-                        .to_vec(),
-                    headers: Vec::new(),
-                    status_code: 405,
-                    streaming_strategy: None,
-                },
-            }
-        }
-        Err(err) => HttpResponse {
-            body: ["Permission denied. Could not perform this operation. ", err]
-                .join("")
-                .as_bytes()
-                .to_vec(),
-            headers: Vec::new(),
-            status_code: 405,
-            streaming_strategy: None,
-        },
+        Ok(asset) => build_asset_response(&asset, &url, &request_headers, certificate_version),
+        Err(error) => build_error_response(error, &url, &request_headers, certificate_version),
     }
 }
 
 #[query]
 fn http_request_streaming_callback(
-    StreamingCallbackToken { token, headers, index, full_path, .. }: StreamingCallbackToken,
+    StreamingCallbackToken {
+        token,
+        headers,
+        range,
+        full_path,
+        encoding_type,
+        sha256,
+        ..
+    }: StreamingCallbackToken,
 ) -> StreamingCallbackHttpResponse {
-    let result = get_asset(&full_path, token);
+    let result = get_asset(&full_path, token.clone());
 
     match result {
-        Err(err) => trap(["Streamed asset not found: ", err].join("")),
+        Err(err) => trap(["Streamed asset not found: ", err.into()].join("")),
         Ok(asset) => {
-            let encoding = asset.encoding_raw();
+            let encoding = asset
+                .encodings
+                .get(&encoding_type)
+                .unwrap_or_else(|| trap("Streamed encoding not found."));
+
+            // The chunks of a content replaced during the stream would corrupt the body
+            if sha256.is_some_and(|sha256| sha256.as_slice() != encoding.sha256) {
+                trap("Streamed asset has been replaced.");
+            }
+
+            let (body, remaining) = get_range_content(encoding, &range)
+                .unwrap_or_else(|err| trap(["Streamed range not found: ", err].join("")));
 
             StreamingCallbackHttpResponse {
-                token: create_token(&asset.key, index, encoding, &headers),
-                body: encoding.content_chunks[index].clone(),
+                token: create_token(
+                    &asset.key,
+                    token.as_ref(),
+                    &encoding_type,
+                    remaining,
+                    encoding,
+                    &headers,
+                ),
+                body,
             }
         }
     }
 }
 
-#[update]
-fn init_upload(key: AssetKey) -> InitUpload {
+#[update(guard = "caller_can_upload")]
+async fn init_upload(key: AssetKey) -> Result<InitUpload, BucketError> {
     println!("{:?}", "upload starts...");
-    // let _user: Principal = STATE.with(|state| state.borrow().stable.user).unwrap();
 
-    // if principal_not_equal(caller(), user) {
-    //     trap("User does not have the permission to upload data.");
-    // }
+    if !has_batch_secret() {
+        init_batch_secret(draw_random_bytes().await?);
+    }
 
-    let batch_id = create_batch(key);
-    println!("{batch_id:?}");
-    InitUpload { batch_id }
+    let result = create_batch(key, msg_caller());
+    println!("{result:?}");
+    result.map(|batch_id| InitUpload { batch_id })
 }
 
-#[update]
-fn upload_chunk(chunk: Chunk) -> UploadChunk {
+#[update(guard = "caller_can_upload")]
+fn upload_chunk(chunk: Chunk) -> Result<UploadChunk, BucketError> {
     println!("{:?}", "chunks upload...");
-    // let _user: Principal = STATE.with(|state| state.borrow().stable.user).unwrap();
 
-    // if principal_not_equal(caller(), user) {
-    //     trap("User does not have the permission to a upload any chunks of content.");
-    // }
+    let result = create_chunk(chunk, msg_caller());
 
-    let result = create_chunk(chunk);
+    result.map(|(chunk_id, index)| UploadChunk { chunk_id, index })
+}
 
-    match result {
-        Ok(chunk_id) => UploadChunk { chunk_id },
-        Err(error) => trap(error),
-    }
+#[query(guard = "caller_can_upload")]
+fn batch_chunks(batch_id: u128) -> Result<BatchChunks, BucketError> {
+    get_batch_chunks(batch_id, msg_caller())
 }
 
-#[update]
-fn commit_upload(commit: CommitBatch) {
+#[update(guard = "caller_can_upload")]
+fn commit_upload(commit: CommitBatch) -> Result<(), BucketError> {
     println!("{:?}", "commit upload...");
-    // let _user: Principal = STATE.with(|state| state.borrow().stable.user).unwrap();
-
-    // if principal_not_equal(caller(), user) {
-    //     trap("User does not have the permission to commit an upload.");
-    // }
 
-    let result = commit_batch(commit);
+    let result = commit_batch(commit, msg_caller());
     println!("{result:?}");
-    match result {
-        Ok(_) => (),
-        Err(error) => trap(error),
-    }
+    result.map(|_| ())
 }
 
-#[query]
+#[query(guard = "caller_can_upload")]
+fn list_pending_batches() -> Vec<PendingBatch> {
+    get_pending_batches(msg_caller())
+}
+
+#[query(guard = "caller_can_read")]
 fn list(folder: Option<String>) -> Vec<AssetKey> {
-    // let _user: Principal = STATE.with(|state| state.borrow().stable.user).unwrap();
+    get_keys(folder)
+}
+
+#[query(guard = "caller_can_read")]
+fn list_assets(params: ListParams) -> ListResults {
+    list_keys(params)
+}
 
-    // if principal_not_equal(caller(), user) {
-    //     trap("User does not have the permission to list the assets.");
-    // }
+#[query(guard = "caller_can_read")]
+fn list_folder(path: String) -> FolderListing {
+    store::list_folder(&path)
+}
 
-    get_keys(folder)
+#[query(guard = "caller_can_read")]
+fn folder_stats(path: String) -> FolderStats {
+    get_folder_stats(&path)
+}
+
+#[update(guard = "caller_can_upload")]
+fn move_folder(from: String, to: String) -> Result<(), BucketError> {
+    store::move_folder(&from, &to)
 }
 
 #[query]
@@ -210,32 +267,191 @@ const fn test() -> u8 {
     2
 }
 
-#[update]
-fn del(param: Del) {
-    let _user: Principal = STATE.with(|state| state.borrow().stable.user).unwrap();
+#[update(guard = "caller_can_upload")]
+fn del(param: Del) -> Result<(), BucketError> {
+    let result = delete_asset(param);
 
-    // if principal_not_equal(caller(), user) {
-    //     trap("User does not have the permission to delete an asset.");
-    // }
+    result.map(|_| ())
+}
 
-    let result = delete_asset(param);
+#[query(guard = "caller_is_owner")]
+fn cycles_balance() -> u128 {
+    canister_cycle_balance()
+}
 
-    match result {
-        Ok(_) => (),
-        Err(error) => trap(["Asset cannot be deleted: ", error].join("")),
+//
+// Deploys
+//
+
+#[update(guard = "caller_can_upload")]
+fn init_deploy() -> InitDeploy {
+    InitDeploy {
+        deploy_id: create_deploy(msg_caller()),
     }
 }
 
-#[query]
-fn cycles_balance() -> u128 {
-    let _caller = msg_caller();
-    let _user: Principal = STATE.with(|state| state.borrow().stable.user).unwrap();
+#[update(guard = "caller_can_upload")]
+fn stage_upload(deploy_id: u128, commit: CommitBatch) -> Result<(), BucketError> {
+    stage_batch(deploy_id, commit, msg_caller())
+}
 
-    // if !is_manager(caller) && principal_not_equal(caller, user) {
-    //     trap("No permission to read the balance of the cycles.");
-    // }
+#[update(guard = "caller_can_upload")]
+fn stage_delete(deploy_id: u128, param: Del) -> Result<(), BucketError> {
+    stage_deletion(deploy_id, param, msg_caller())
+}
 
-    canister_cycle_balance()
+#[update(guard = "caller_can_upload")]
+fn commit_deploy(deploy_id: u128) -> Result<(), BucketError> {
+    store::commit_deploy(deploy_id, msg_caller())
+}
+
+#[update(guard = "caller_can_upload")]
+fn abort_deploy(deploy_id: u128) -> Result<(), BucketError> {
+    store::abort_deploy(deploy_id, msg_caller())
+}
+
+//
+// Revisions
+//
+
+#[query(guard = "caller_can_upload")]
+fn list_revisions(full_path: String) -> Vec<AssetRevision> {
+    get_revisions(&full_path)
+}
+
+#[query(guard = "caller_can_upload")]
+fn revision_chunk(param: GetRevisionChunk) -> Result<ByteBuf, BucketError> {
+    get_revision_chunk(param).map(ByteBuf::from)
+}
+
+#[update(guard = "caller_can_upload")]
+fn rollback(full_path: String, revision: u64) -> Result<(), BucketError> {
+    rollback_asset(full_path, revision)
+}
+
+//
+// Permissions
+//
+
+#[update(guard = "caller_is_owner")]
+fn grant(permission: Permission) -> Result<(), BucketError> {
+    grant_permission(permission)
+}
+
+#[update(guard = "caller_is_owner")]
+fn revoke(principal: Principal) -> Result<(), BucketError> {
+    revoke_permission(principal)
+}
+
+#[query(guard = "caller_is_owner")]
+fn list_permissions() -> Vec<Permission> {
+    get_permissions()
+}
+
+//
+// Access tokens
+//
+
+#[update(guard = "caller_can_upload")]
+async fn create_access_token(params: CreateAccessToken) -> Result<AccessToken, BucketError> {
+    if !has_token_secret() {
+        init_token_secret(draw_random_bytes().await?);
+    }
+
+    store::create_access_token(params)
+}
+
+#[update(guard = "caller_can_upload")]
+fn revoke_access_token(token: String) -> Result<(), BucketError> {
+    store::revoke_access_token(&token)
+}
+
+/// Invalidates every access token issued so far
+#[update(guard = "caller_is_owner")]
+async fn rotate_token_secret() -> Result<(), BucketError> {
+    store::rotate_token_secret(draw_random_bytes().await?);
+    Ok(())
+}
+
+async fn draw_random_bytes() -> Result<Vec<u8>, BucketError> {
+    raw_rand()
+        .await
+        .map_err(|err| BucketError::RandomnessUnavailable {
+            reason: err.to_string(),
+        })
+}
+
+//
+// Fallback
+//
+
+#[update(guard = "caller_is_owner")]
+fn set_fallback_asset(full_path: Option<String>) -> Result<(), BucketError> {
+    set_fallback(full_path)
+}
+
+#[query(guard = "caller_is_owner")]
+fn fallback_asset() -> Option<String> {
+    get_fallback()
+}
+
+//
+// Cache-Control
+//
+
+#[update(guard = "caller_is_owner")]
+fn set_cache_control(rules: Vec<CacheControlRule>) -> Result<(), BucketError> {
+    set_cache_control_rules(rules)
+}
+
+#[query(guard = "caller_is_owner")]
+fn list_cache_control() -> Vec<CacheControlRule> {
+    get_cache_control_rules()
+}
+
+//
+// Routing
+//
+
+#[update(guard = "caller_is_owner")]
+fn set_routing(routing: RoutingConfig) -> Result<(), BucketError> {
+    store::set_routing(routing)
+}
+
+#[query(guard = "caller_is_owner")]
+fn routing() -> RoutingConfig {
+    get_routing()
+}
+
+//
+// Quotas
+//
+
+#[update(guard = "caller_is_owner")]
+fn set_quotas(quotas: Quotas) {
+    store::set_quotas(quotas);
+}
+
+#[query(guard = "caller_can_upload")]
+fn usage() -> Usage {
+    get_usage(msg_caller())
+}
+
+//
+// Garbage collection
+//
+
+// Time between two sweeps of the expired upload batches
+const BATCHES_SWEEP_INTERVAL: Duration = Duration::from_secs(60);
+
+#[query(guard = "caller_is_owner")]
+fn gc_stats() -> GcStats {
+    get_gc_stats()
+}
+
+#[update(guard = "caller_is_owner")]
+fn sweep_batches() -> GcStats {
+    sweep_expired_batches()
 }
 
 export_candid!();
@@ -0,0 +1,51 @@
+use ic_stable_structures::{
+    memory_manager::{MemoryId, MemoryManager, VirtualMemory},
+    DefaultMemoryImpl, StableBTreeMap, StableCell,
+};
+use std::cell::RefCell;
+
+use crate::types::state::{HeapState, StableState};
+
+pub type Memory = VirtualMemory<DefaultMemoryImpl>;
+
+const UPGRADES_MEMORY_ID: MemoryId = MemoryId::new(0);
+const ASSETS_MEMORY_ID: MemoryId = MemoryId::new(1);
+const CONTENT_CHUNKS_MEMORY_ID: MemoryId = MemoryId::new(2);
+const REVISIONS_MEMORY_ID: MemoryId = MemoryId::new(3);
+
+thread_local! {
+  static MEMORY_MANAGER: RefCell<MemoryManager<DefaultMemoryImpl>> =
+        RefCell::new(MemoryManager::init(DefaultMemoryImpl::default()));
+}
+
+fn get_memory(id: MemoryId) -> Memory {
+    MEMORY_MANAGER.with_borrow(|memory_manager| memory_manager.get(id))
+}
+
+pub fn init_stable_state() -> StableState {
+    StableState {
+        assets: StableBTreeMap::init(get_memory(ASSETS_MEMORY_ID)),
+        content_chunks: StableBTreeMap::init(get_memory(CONTENT_CHUNKS_MEMORY_ID)),
+        revisions: StableBTreeMap::init(get_memory(REVISIONS_MEMORY_ID)),
+    }
+}
+
+//
+// The heap state only holds the settings of the bucket, it is small enough to be written
+// in a dedicated memory on each upgrade.
+//
+
+pub fn save_heap_state(heap: &HeapState) {
+    let mut cell = StableCell::init(get_memory(UPGRADES_MEMORY_ID), HeapState::default())
+        .expect("Failed to init the upgrades memory");
+
+    cell.set(heap.clone())
+        .expect("Failed to save the heap state");
+}
+
+pub fn restore_heap_state() -> HeapState {
+    StableCell::init(get_memory(UPGRADES_MEMORY_ID), HeapState::default())
+        .expect("Failed to init the upgrades memory")
+        .get()
+        .clone()
+}
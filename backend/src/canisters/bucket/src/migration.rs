@@ -0,0 +1,122 @@
+use candid::{CandidType, Deserialize, Principal};
+use ic_cdk::stable::{stable_read, stable_size};
+use ic_cdk::storage::stable_restore;
+use ic_certified_map::Hash;
+use sha2::{Digest, Sha256};
+use std::collections::HashMap;
+
+use crate::store::insert_content_chunks;
+use crate::types::http::HeaderField;
+use crate::types::state::{HeapState, Quotas, RoutingConfig, State, StorageUsage};
+use crate::types::store::{Asset, AssetEncoding, AssetKey};
+
+//
+// Layout of the state written with stable_save before the assets moved to stable structures
+//
+
+const CANDID_MAGIC: &[u8; 4] = b"DIDL";
+
+#[derive(CandidType, Deserialize)]
+pub struct LegacyStableState {
+    user: Option<Principal>,
+    assets: HashMap<String, LegacyAsset>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LegacyAsset {
+    key: AssetKey,
+    headers: Vec<HeaderField>,
+    encodings: HashMap<String, LegacyAssetEncoding>,
+}
+
+#[derive(CandidType, Deserialize)]
+struct LegacyAssetEncoding {
+    modified: u64,
+    content_chunks: Vec<Vec<u8>>,
+    total_length: u128,
+    sha256: Hash,
+}
+
+/// Reads the legacy state if the stable memory still begins with a candid message rather than
+/// the header of the memory manager. Must run before the stable structures are initialized.
+pub fn restore_legacy_state() -> Option<LegacyStableState> {
+    if stable_size() == 0 {
+        return None;
+    }
+
+    let mut magic = [0; 4];
+    stable_read(0, &mut magic);
+
+    if &magic != CANDID_MAGIC {
+        return None;
+    }
+
+    let (legacy,): (LegacyStableState,) =
+        stable_restore().expect("Failed to restore the legacy stable state");
+
+    Some(legacy)
+}
+
+pub fn migrate_legacy_state(
+    LegacyStableState { user, assets }: LegacyStableState,
+    state: &mut State,
+) {
+    state.heap = HeapState {
+        user,
+        permissions: HashMap::new(),
+        quotas: Quotas::default(),
+        fallback: None,
+        cache_control_rules: Vec::new(),
+        routing: RoutingConfig::default(),
+        deploys: HashMap::new(),
+        token_secret: None,
+        next_token_id: 0,
+        revoked_tokens: HashMap::new(),
+        batch_secret: None,
+        batch_count: 0,
+        chunk_count: 0,
+        deploy_count: 0,
+        usage: StorageUsage::default(),
+    };
+
+    for (full_path, legacy_asset) in assets {
+        let mut encodings = HashMap::new();
+
+        for (encoding_type, legacy_encoding) in legacy_asset.encodings {
+            let chunk_lengths = legacy_encoding
+                .content_chunks
+                .iter()
+                .map(|chunk| chunk.len() as u128)
+                .collect();
+            let chunk_sha256s = legacy_encoding
+                .content_chunks
+                .iter()
+                .map(|chunk| Sha256::digest(chunk).into())
+                .collect();
+            let content_chunks = insert_content_chunks(legacy_encoding.content_chunks, None, state);
+
+            encodings.insert(
+                encoding_type,
+                AssetEncoding {
+                    modified: legacy_encoding.modified,
+                    content_chunks,
+                    chunk_lengths,
+                    chunk_sha256s,
+                    total_length: legacy_encoding.total_length,
+                    sha256: legacy_encoding.sha256,
+                    uploaded_by: None,
+                },
+            );
+        }
+
+        state.stable.assets.insert(
+            full_path,
+            Asset {
+                key: legacy_asset.key,
+                headers: legacy_asset.headers,
+                encodings,
+                commit: None,
+            },
+        );
+    }
+}
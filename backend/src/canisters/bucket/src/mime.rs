@@ -0,0 +1,98 @@
+use ic_cdk::println;
+
+use crate::types::http::HeaderField;
+use crate::types::store::AssetKey;
+
+const CONTENT_TYPE: &str = "Content-Type";
+const DEFAULT_CONTENT_TYPE: &str = "application/octet-stream";
+
+// Signatures at the start of the binary formats commonly served
+const MAGIC_BYTES: [(&[u8], &str); 14] = [
+    (b"\x89PNG\r\n\x1a\n", "image/png"),
+    (b"\xff\xd8\xff", "image/jpeg"),
+    (b"GIF87a", "image/gif"),
+    (b"GIF89a", "image/gif"),
+    (b"\0\0\x01\0", "image/x-icon"),
+    (b"%PDF-", "application/pdf"),
+    (b"\0asm", "application/wasm"),
+    (b"\x1f\x8b", "application/gzip"),
+    (b"PK\x03\x04", "application/zip"),
+    (b"wOFF", "font/woff"),
+    (b"wOF2", "font/woff2"),
+    (b"OggS", "audio/ogg"),
+    (b"ID3", "audio/mpeg"),
+    (b"\x1aE\xdf\xa3", "video/webm"),
+];
+
+/// Headers of a raw content with a Content-Type. An explicit header overrides the type inferred
+/// from the extension of the asset or, for lack of a known extension, from its magic bytes.
+/// A type that does not match the magic bytes is accepted but reported.
+pub fn with_content_type(
+    headers: Vec<HeaderField>,
+    key: &AssetKey,
+    first_chunk: &[u8],
+) -> Vec<HeaderField> {
+    let sniffed = sniff_content_type(first_chunk);
+
+    let explicit = headers
+        .iter()
+        .find(|HeaderField(name, _)| name.eq_ignore_ascii_case(CONTENT_TYPE))
+        .map(|HeaderField(_, value)| value.clone());
+
+    if let Some(explicit) = explicit {
+        warn_mismatch(&key.full_path, &explicit, sniffed);
+        return headers;
+    }
+
+    let content_type = match extension_content_type(&key.full_path) {
+        Some(content_type) => {
+            warn_mismatch(&key.full_path, &content_type, sniffed);
+            content_type
+        }
+        None => sniffed.unwrap_or(DEFAULT_CONTENT_TYPE).to_string(),
+    };
+
+    [
+        headers,
+        vec![HeaderField(CONTENT_TYPE.to_string(), content_type)],
+    ]
+    .concat()
+}
+
+fn extension_content_type(full_path: &str) -> Option<String> {
+    mime_guess::from_path(full_path)
+        .first()
+        .map(|mime| mime.essence_str().to_string())
+}
+
+fn sniff_content_type(content: &[u8]) -> Option<&'static str> {
+    // Containers identified by a brand after their header
+    match content.get(..12) {
+        Some([b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P']) => {
+            return Some("image/webp")
+        }
+        Some([_, _, _, _, b'f', b't', b'y', b'p', brand @ ..]) => {
+            return Some(match brand {
+                b"avif" => "image/avif",
+                _ => "video/mp4",
+            })
+        }
+        _ => (),
+    }
+
+    MAGIC_BYTES
+        .iter()
+        .find(|(magic, _)| content.starts_with(magic))
+        .map(|(_, content_type)| *content_type)
+}
+
+fn warn_mismatch(full_path: &str, content_type: &str, sniffed: Option<&str>) {
+    let essence = content_type.split(';').next().unwrap_or_default().trim();
+
+    match sniffed {
+        Some(sniffed) if !essence.eq_ignore_ascii_case(sniffed) => println!(
+            "Content-Type {content_type} of {full_path} does not match its content, which looks like {sniffed}."
+        ),
+        _ => (),
+    }
+}
@@ -0,0 +1,88 @@
+use crate::types::state::{Assets, RoutingConfig};
+use crate::types::store::Asset;
+
+//
+// Resolution of the request urls to the assets, according to the routing of the bucket. The
+// urls that may resolve to an asset are certified with its responses, see `alias_urls`.
+//
+
+/// Asset served for the url: the asset at its path or, per the routing, the index of the folder
+/// or the html page named after it
+pub fn resolve_url(url: &str, routing: &RoutingConfig, assets: &Assets) -> Option<Asset> {
+    candidate_paths(url, routing)
+        .iter()
+        .find_map(|full_path| assets.get(full_path))
+}
+
+/// Urls that may resolve to the asset, the full path itself first. An url resolves to another
+/// asset if one with a higher precedence exists.
+pub fn alias_urls(full_path: &str, routing: &RoutingConfig) -> Vec<String> {
+    let mut urls = vec![full_path.to_string()];
+
+    if full_path == "/" {
+        urls.push("/index.html".to_string());
+    }
+
+    // The url of the folder is certified with and without its trailing slash
+    if let Some(index_file) = &routing.index_file {
+        if let Some(folder) = full_path.strip_suffix(&format!("/{index_file}")) {
+            if !folder.is_empty() {
+                urls.push(folder.to_string());
+            }
+
+            urls.push(format!("{folder}/"));
+        }
+    }
+
+    if routing.html_extension {
+        if let Some(url) = full_path.strip_suffix(".html") {
+            if is_extensionless(url) {
+                urls.push(url.to_string());
+            }
+        }
+    }
+
+    urls
+}
+
+/// Status of the fallback asset: the entry point of a single page app answers the routes it
+/// handles itself, a not found page answers with a 404
+pub fn fallback_status(routing: &RoutingConfig) -> u16 {
+    match routing.spa_fallback {
+        true => 200,
+        false => 404,
+    }
+}
+
+// Full paths looked up for the url, by order of precedence
+fn candidate_paths(url: &str, routing: &RoutingConfig) -> Vec<String> {
+    let mut full_paths = Vec::new();
+
+    // Map /index.html to / because we are using / as root
+    if url == "/index.html" {
+        full_paths.push("/".to_string());
+    }
+
+    full_paths.push(url.to_string());
+
+    if let Some(index_file) = &routing.index_file {
+        full_paths.push(match url.ends_with('/') {
+            true => format!("{url}{index_file}"),
+            false => format!("{url}/{index_file}"),
+        });
+    }
+
+    if routing.html_extension && is_extensionless(url) {
+        full_paths.push(format!("{url}.html"));
+    }
+
+    full_paths
+}
+
+// The urls are normalized, the root and the paths with a trailing slash have no last segment
+fn is_extensionless(url: &str) -> bool {
+    match url.rsplit_once('/') {
+        Some((_, name)) => !name.is_empty() && !name.contains('.'),
+        None => false,
+    }
+}
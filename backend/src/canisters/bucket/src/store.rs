@@ -1,44 +1,62 @@
+use candid::Principal;
 use ic_cdk::{api::time, println};
-use std::collections::HashMap;
+use serde_bytes::ByteBuf;
+use std::cmp::Ordering;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::ops::Bound;
 
 use crate::cert::update_certified_data;
-use crate::impls::ASSET_ENCODING_KEY_RAW;
-use crate::types::interface::{CommitBatch, Del};
-use crate::types::state::{RuntimeState, StableState, State};
-use crate::types::store::{Asset, AssetEncoding, AssetKey, Batch, Chunk};
+use crate::impls::{ASSET_ENCODING_KEYS_PREFERENCE, ASSET_ENCODING_KEY_RAW};
+use crate::mime::with_content_type;
+use crate::routing::{alias_urls, fallback_status, resolve_url};
+use crate::token::{derive_id, sign_token, verify_token};
+use crate::types::assets::AssetHashes;
+use crate::types::http::RequestUrl;
+use crate::types::interface::{
+    AccessToken, AssetRevision, BatchChunks, BucketError, CommitBatch, CreateAccessToken, Del,
+    FolderListing, FolderStats, GcStats, GetRevisionChunk, ListCursor, ListOrder, ListParams,
+    ListResults, PendingBatch, Permission, TokenScope, Usage,
+};
+use crate::types::state::{
+    CacheControlPattern, CacheControlRule, HeapState, Quotas, RoutingConfig, RuntimeState,
+    StableState, State,
+};
+use crate::types::store::{
+    Asset, AssetCommit, AssetEncoding, AssetKey, AssetRevisions, Batch, Chunk, Deploy,
+    GetAssetError, TokenClaims, UploadedChunk,
+};
+use crate::url::parse_url;
 use crate::STATE;
 
 //
 // Getter, list and delete
 //
 
-pub fn get_asset_for_url(url: &str) -> Result<Asset, &'static str> {
+pub fn get_asset_for_url(url: &str) -> Result<Asset, GetAssetError> {
     if url.is_empty() {
-        return Err("No url provided.");
+        return Err(GetAssetError::BadRequest("No url provided."));
     }
 
-    let split: Vec<&str> = url.split("?token=").collect();
-    let full_path: &str = &["/", split[0].trim_start_matches('/')].join("");
+    let RequestUrl { path, params } = parse_url(url).map_err(GetAssetError::BadRequest)?;
 
     // Token protected assets
-    if split.len() > 1 {
-        let token = split[1];
-        return get_asset(full_path, Some(token.to_string()));
-    }
+    let token = params.get("token").cloned();
 
-    // Map /index.html to / because we are using / as root
-    if full_path == "/index.html" {
-        return get_asset("/", None);
-    };
+    STATE.with(|state| {
+        let state = &state.borrow();
 
-    get_asset(full_path, None)
+        let full_path = resolve_url(&path, &state.heap.routing, &state.stable.assets)
+            .map_or(path, |asset| asset.key.full_path);
+
+        get_asset_impl(&full_path, token, state)
+    })
 }
 
-pub fn get_asset(full_path: &str, token: Option<String>) -> Result<Asset, &'static str> {
-    STATE.with(|state| get_asset_impl(full_path, token, &state.borrow().stable))
+pub fn get_asset(full_path: &str, token: Option<String>) -> Result<Asset, GetAssetError> {
+    STATE.with(|state| get_asset_impl(full_path, token, &state.borrow()))
 }
 
-pub fn delete_asset(param: Del) -> Result<Asset, &'static str> {
+pub fn delete_asset(param: Del) -> Result<Asset, BucketError> {
     STATE.with(|state| delete_asset_impl(param, &mut state.borrow_mut()))
 }
 
@@ -46,50 +64,62 @@ pub fn get_keys(folder: Option<String>) -> Vec<AssetKey> {
     STATE.with(|state| get_keys_impl(folder, &state.borrow().stable))
 }
 
+pub fn list_keys(params: ListParams) -> ListResults {
+    STATE.with(|state| list_keys_impl(params, &state.borrow().stable))
+}
+
 pub fn get_len() -> usize {
     println!("{:?}", "len of all cdn files...");
-    STATE.with(|state| state.borrow().stable.assets.len())
+    STATE.with(|state| state.borrow().stable.assets.len() as usize)
+}
+
+pub fn get_content_chunk(encoding: &AssetEncoding, index: usize) -> Result<Vec<u8>, &'static str> {
+    STATE.with(|state| get_content_chunk_impl(encoding, index, &state.borrow().stable))
 }
 
 fn get_asset_impl(
     full_path: &str,
     token: Option<String>,
-    state: &StableState,
-) -> Result<Asset, &'static str> {
-    let asset = state.assets.get(full_path);
+    state: &State,
+) -> Result<Asset, GetAssetError> {
+    let asset = state.stable.assets.get(&full_path.to_string());
 
     match asset {
-        None => Err("No asset."),
-        Some(asset) => match &asset.key.id {
-            None => Ok(asset.clone()),
-            Some(asset_token) => get_protected_asset(asset, asset_token, token),
-        },
+        None => Err(GetAssetError::NotFound("No asset.")),
+        Some(asset) if asset.key.id.is_none() => Ok(asset),
+        Some(asset) => get_protected_asset(asset, token, &state.heap),
     }
 }
 
+// A protected asset is served with a signed access token only, its id merely marks it as
+// protected and grants no access
 fn get_protected_asset(
-    asset: &Asset,
-    asset_token: &String,
+    asset: Asset,
     token: Option<String>,
-) -> Result<Asset, &'static str> {
+    state: &HeapState,
+) -> Result<Asset, GetAssetError> {
     match token {
-        None => Err("No token provided."),
-        Some(token) => {
-            if &token == asset_token {
-                return Ok(asset.clone());
-            }
-
-            Err("Invalid token.")
-        }
+        None => Err(GetAssetError::Forbidden("No token provided.")),
+        Some(token) => verify_access_token(&token, &asset.key.full_path, state)
+            .map(|()| asset)
+            .map_err(GetAssetError::Forbidden),
     }
 }
 
-fn get_keys_impl(folder: Option<String>, state: &StableState) -> Vec<AssetKey> {
-    fn map_key(asset: &Asset) -> AssetKey {
-        asset.key.clone()
-    }
+fn get_content_chunk_impl(
+    encoding: &AssetEncoding,
+    index: usize,
+    state: &StableState,
+) -> Result<Vec<u8>, &'static str> {
+    encoding
+        .content_chunks
+        .get(index)
+        .and_then(|chunk_id| state.content_chunks.get(chunk_id))
+        .ok_or("No content for this chunk.")
+}
 
-    let all_keys: Vec<AssetKey> = state.assets.values().map(map_key).collect();
+fn get_keys_impl(folder: Option<String>, state: &StableState) -> Vec<AssetKey> {
+    let all_keys: Vec<AssetKey> = state.assets.values().map(|asset| asset.key).collect();
 
     match folder {
         Some(folder) => all_keys
@@ -100,16 +130,91 @@ fn get_keys_impl(folder: Option<String>, state: &StableState) -> Vec<AssetKey> {
     }
 }
 
+const DEFAULT_LIST_LIMIT: u32 = 100;
+const MAX_LIST_LIMIT: u32 = 1000;
+
+fn list_keys_impl(
+    ListParams {
+        prefix,
+        order,
+        cursor,
+        limit,
+        omit_preview,
+    }: ListParams,
+    state: &StableState,
+) -> ListResults {
+    let prefix = prefix.unwrap_or_default();
+    let order = order.unwrap_or(ListOrder::FullPath);
+    let limit = limit.unwrap_or(DEFAULT_LIST_LIMIT).clamp(1, MAX_LIST_LIMIT) as usize;
+
+    // The assets are stored by full path, the keys of a prefix are a range of the map
+    let mut keys: Vec<AssetKey> = match order {
+        // The page is read from the cursor on, no key before it is decoded
+        ListOrder::FullPath => {
+            let start = match &cursor {
+                Some(cursor) if cursor.full_path >= prefix => {
+                    Bound::Excluded(cursor.full_path.clone())
+                }
+                _ => Bound::Included(prefix.clone()),
+            };
+
+            state
+                .assets
+                .range((start, Bound::Unbounded))
+                .take_while(|(full_path, _)| full_path.starts_with(&prefix))
+                .map(|(_, asset)| asset.key)
+                .take(limit + 1)
+                .collect()
+        }
+        ListOrder::Created | ListOrder::Size | ListOrder::Name => {
+            let is_after_cursor = |key: &AssetKey| {
+                cursor.as_ref().is_none_or(|cursor| {
+                    order.compare(&ListCursor::from(key), cursor) == Ordering::Greater
+                })
+            };
+
+            let mut keys: Vec<AssetKey> = state
+                .assets
+                .range(prefix.clone()..)
+                .take_while(|(full_path, _)| full_path.starts_with(&prefix))
+                .map(|(_, asset)| asset.key)
+                .filter(is_after_cursor)
+                .collect();
+            keys.sort_by(|lhs, rhs| order.compare(&lhs.into(), &rhs.into()));
+            keys.truncate(limit + 1);
+            keys
+        }
+    };
+
+    let next_cursor = if keys.len() > limit {
+        keys.truncate(limit);
+        keys.last().map(ListCursor::from)
+    } else {
+        None
+    };
+
+    if omit_preview {
+        for key in &mut keys {
+            key.preview = None;
+        }
+    }
+
+    ListResults { keys, next_cursor }
+}
+
 fn delete_asset_impl(
     Del { full_path, token }: Del,
     state: &mut State,
-) -> Result<Asset, &'static str> {
-    let result = get_asset_impl(&full_path, token, &state.stable);
+) -> Result<Asset, BucketError> {
+    let result = get_asset_impl(&full_path, token, state);
 
     match result {
-        Err(err) => Err(err),
+        Err(GetAssetError::Forbidden(_)) => Err(BucketError::InvalidToken { full_path }),
+        Err(GetAssetError::BadRequest(_) | GetAssetError::NotFound(_)) => {
+            Err(BucketError::AssetNotFound { full_path })
+        }
         Ok(asset) => {
-            state.stable.assets.remove(&*full_path);
+            remove_asset(&full_path, state);
             delete_certified_asset(state, &full_path);
             Ok(asset)
         }
@@ -117,208 +222,1749 @@ fn delete_asset_impl(
 }
 
 //
-// Upload batch and chunks
+// Permissions
 //
 
-const BATCH_EXPIRY_NANOS: u64 = 300_000_000_000;
+pub fn grant_permission(permission: Permission) -> Result<(), BucketError> {
+    STATE.with(|state| grant_permission_impl(permission, &mut state.borrow_mut().heap))
+}
+
+pub fn revoke_permission(principal: Principal) -> Result<(), BucketError> {
+    STATE.with(|state| revoke_permission_impl(principal, &mut state.borrow_mut().heap))
+}
+
+pub fn get_permissions() -> Vec<Permission> {
+    STATE.with(|state| get_permissions_impl(&state.borrow().heap))
+}
+
+fn grant_permission_impl(
+    Permission { principal, role }: Permission,
+    state: &mut HeapState,
+) -> Result<(), BucketError> {
+    if principal == Principal::anonymous() {
+        return Err(BucketError::InvalidPermission {
+            principal,
+            reason: "Anonymous principal cannot be granted a role.".to_string(),
+        });
+    }
+
+    if state.user == Some(principal) {
+        return Err(BucketError::InvalidPermission {
+            principal,
+            reason: "The user of the bucket is always an owner.".to_string(),
+        });
+    }
+
+    state.permissions.insert(principal, role);
+
+    Ok(())
+}
+
+fn revoke_permission_impl(principal: Principal, state: &mut HeapState) -> Result<(), BucketError> {
+    if state.user == Some(principal) {
+        return Err(BucketError::InvalidPermission {
+            principal,
+            reason: "The user of the bucket cannot be revoked.".to_string(),
+        });
+    }
+
+    match state.permissions.remove(&principal) {
+        None => Err(BucketError::PermissionNotFound { principal }),
+        Some(_) => Ok(()),
+    }
+}
+
+fn get_permissions_impl(state: &HeapState) -> Vec<Permission> {
+    state
+        .permissions
+        .iter()
+        .map(|(principal, role)| Permission {
+            principal: *principal,
+            role: *role,
+        })
+        .collect()
+}
+
+//
+// Access tokens
+//
 
-static mut NEXT_BACK_ID: u128 = 0;
-static mut NEXT_CHUNK_ID: u128 = 0;
+// Longest validity of a signed access token
+const MAX_TOKEN_VALIDITY_NANOS: u64 = 30 * 24 * 60 * 60 * 1_000_000_000;
 
-pub fn create_batch(key: AssetKey) -> u128 {
-    STATE.with(|state| create_batch_impl(key, &mut state.borrow_mut().runtime))
+pub fn has_token_secret() -> bool {
+    STATE.with(|state| state.borrow().heap.token_secret.is_some())
 }
 
-pub fn create_chunk(chunk: Chunk) -> Result<u128, &'static str> {
-    STATE.with(|state| create_chunk_impl(chunk, &mut state.borrow_mut().runtime))
+/// Sets the key of the access tokens unless one has been set in the meantime
+pub fn init_token_secret(secret: Vec<u8>) {
+    STATE.with(|state| {
+        state.borrow_mut().heap.token_secret.get_or_insert(secret);
+    })
 }
 
-pub fn commit_batch(commit_batch: CommitBatch) -> Result<&'static str, &'static str> {
-    STATE.with(|state| commit_batch_impl(commit_batch, &mut state.borrow_mut()))
+/// Replaces the key of the access tokens, which invalidates all the tokens issued so far
+pub fn rotate_token_secret(secret: Vec<u8>) {
+    STATE.with(|state| {
+        let heap = &mut state.borrow_mut().heap;
+        heap.token_secret = Some(secret);
+        heap.revoked_tokens.clear();
+    })
 }
 
-fn create_batch_impl(key: AssetKey, state: &mut RuntimeState) -> u128 {
+pub fn create_access_token(params: CreateAccessToken) -> Result<AccessToken, BucketError> {
+    STATE.with(|state| create_access_token_impl(params, &mut state.borrow_mut().heap))
+}
+
+pub fn revoke_access_token(token: &str) -> Result<(), BucketError> {
+    STATE.with(|state| revoke_access_token_impl(token, &mut state.borrow_mut().heap))
+}
+
+fn create_access_token_impl(
+    CreateAccessToken {
+        full_path,
+        expires_at,
+        scope,
+    }: CreateAccessToken,
+    state: &mut HeapState,
+) -> Result<AccessToken, BucketError> {
     let now = time();
-    println!("{key:?}");
-    unsafe {
-        clear_expired_batches(state);
+    let max_expires_at = now + MAX_TOKEN_VALIDITY_NANOS;
+
+    if expires_at <= now || expires_at > max_expires_at {
+        return Err(BucketError::InvalidExpiry {
+            expires_at,
+            max_expires_at,
+        });
+    }
+
+    let scope = scope.unwrap_or(TokenScope::Asset);
 
-        NEXT_BACK_ID += 1;
+    let full_path = match scope {
+        TokenScope::Asset => format!("/{}", full_path.trim_start_matches('/')),
+        TokenScope::Folder => folder_path(&full_path),
+    };
+
+    validate_path(&full_path).map_err(|reason| BucketError::InvalidKey {
+        full_path: full_path.clone(),
+        reason,
+    })?;
+
+    let secret = state
+        .token_secret
+        .as_ref()
+        .ok_or(BucketError::RandomnessUnavailable {
+            reason: "No secret to sign the token.".to_string(),
+        })?;
+
+    let token_id = state.next_token_id + 1;
+
+    let token = sign_token(
+        &TokenClaims {
+            token_id,
+            full_path,
+            expires_at,
+            scope,
+        },
+        secret,
+    )
+    .map_err(|reason| BucketError::InvalidAccessToken {
+        reason: reason.to_string(),
+    })?;
+
+    state.next_token_id = token_id;
+
+    Ok(AccessToken {
+        token,
+        token_id,
+        expires_at,
+    })
+}
 
-        state.batches.insert(
-            NEXT_BACK_ID,
-            Batch {
-                key,
-                expires_at: now + BATCH_EXPIRY_NANOS,
-            },
-        );
+fn revoke_access_token_impl(token: &str, state: &mut HeapState) -> Result<(), BucketError> {
+    let now = time();
 
-        NEXT_BACK_ID
+    // An expired token is rejected anyway, its revocation needs not be remembered
+    state
+        .revoked_tokens
+        .retain(|_, expires_at| *expires_at >= now);
+
+    let claims = state
+        .token_secret
+        .as_ref()
+        .ok_or("Invalid token.")
+        .and_then(|secret| verify_token(token, secret))
+        .map_err(|reason| BucketError::InvalidAccessToken {
+            reason: reason.to_string(),
+        })?;
+
+    if claims.expires_at >= now {
+        state
+            .revoked_tokens
+            .insert(claims.token_id, claims.expires_at);
     }
+
+    Ok(())
 }
 
-fn create_chunk_impl(
-    Chunk { batch_id, content }: Chunk,
-    state: &mut RuntimeState,
-) -> Result<u128, &'static str> {
-    let batch = state.batches.get(&batch_id);
+/// Checks that the token has been signed by the bucket, has neither expired nor been revoked
+/// and grants access to the full path
+fn verify_access_token(
+    token: &str,
+    full_path: &str,
+    state: &HeapState,
+) -> Result<(), &'static str> {
+    let secret = state.token_secret.as_ref().ok_or("Invalid token.")?;
+    let claims = verify_token(token, secret)?;
 
-    match batch {
-        None => Err("Batch not found."),
-        Some(b) => {
-            let now = time();
+    if time() > claims.expires_at {
+        return Err("Expired token.");
+    }
 
-            state.batches.insert(
-                batch_id,
-                Batch {
-                    key: b.key.clone(),
-                    expires_at: now + BATCH_EXPIRY_NANOS,
-                },
-            );
+    if state.revoked_tokens.contains_key(&claims.token_id) {
+        return Err("Revoked token.");
+    }
 
-            unsafe {
-                NEXT_CHUNK_ID += 1;
+    let granted = match claims.scope {
+        TokenScope::Asset => claims.full_path == full_path,
+        TokenScope::Folder => full_path.starts_with(&folder_prefix(&claims.full_path)),
+    };
 
-                state
-                    .chunks
-                    .insert(NEXT_CHUNK_ID, Chunk { batch_id, content });
+    if !granted {
+        return Err("Token not valid for this asset.");
+    }
+
+    Ok(())
+}
+
+//
+// Fallback
+//
+
+pub fn set_fallback(full_path: Option<String>) -> Result<(), BucketError> {
+    STATE.with(|state| set_fallback_impl(full_path, &mut state.borrow_mut()))
+}
 
-                Ok(NEXT_CHUNK_ID)
+pub fn get_fallback() -> Option<String> {
+    STATE.with(|state| state.borrow().heap.fallback.clone())
+}
+
+pub fn get_fallback_asset() -> Option<Asset> {
+    STATE.with(|state| get_fallback_asset_impl(&state.borrow()))
+}
+
+fn set_fallback_impl(full_path: Option<String>, state: &mut State) -> Result<(), BucketError> {
+    if let Some(full_path) = &full_path {
+        match state.stable.assets.get(full_path) {
+            None => {
+                return Err(BucketError::AssetNotFound {
+                    full_path: full_path.clone(),
+                })
+            }
+            Some(asset) if asset.key.id.is_some() => {
+                return Err(BucketError::InvalidFallback {
+                    full_path: full_path.clone(),
+                    reason: "A protected asset cannot be a fallback.".to_string(),
+                })
             }
+            Some(_) => (),
         }
     }
+
+    state.heap.fallback = full_path;
+
+    update_certified_fallback(state);
+
+    Ok(())
 }
 
-fn commit_batch_impl(
-    commit_batch: CommitBatch,
-    state: &mut State,
-) -> Result<&'static str, &'static str> {
-    let batches = state.runtime.batches.clone();
-    let batch = batches.get(&commit_batch.batch_id);
+// A fallback that has been deleted or replaced with a protected asset is not served
+fn get_fallback_asset_impl(state: &State) -> Option<Asset> {
+    state
+        .heap
+        .fallback
+        .as_ref()
+        .and_then(|full_path| state.stable.assets.get(full_path))
+        .filter(|asset| asset.key.id.is_none())
+}
 
-    match batch {
-        None => Err("No batch to commit."),
-        Some(b) => {
-            let asset = commit_chunks(commit_batch, b, state);
-            match asset {
-                Err(err) => Err(err),
-                Ok(asset) => {
-                    update_certified_asset(state, &asset);
-                    Ok("Batch committed and certified assets updated.")
-                }
+//
+// Cache-Control
+//
+
+pub fn set_cache_control_rules(rules: Vec<CacheControlRule>) -> Result<(), BucketError> {
+    STATE.with(|state| set_cache_control_rules_impl(rules, &mut state.borrow_mut()))
+}
+
+pub fn get_cache_control_rules() -> Vec<CacheControlRule> {
+    STATE.with(|state| state.borrow().heap.cache_control_rules.clone())
+}
+
+fn set_cache_control_rules_impl(
+    mut rules: Vec<CacheControlRule>,
+    state: &mut State,
+) -> Result<(), BucketError> {
+    for CacheControlRule {
+        pattern,
+        cache_control,
+    } in &mut rules
+    {
+        match pattern {
+            // Compared with the folder of the keys, "/images/" and "images" are "/images"
+            CacheControlPattern::Folder(folder) => *folder = folder_path(folder),
+            CacheControlPattern::Glob(glob) if !glob.starts_with('/') => {
+                return Err(BucketError::InvalidCacheControl {
+                    reason: "A glob must match full paths, starting with a slash.".to_string(),
+                });
             }
+            CacheControlPattern::Glob(_) => (),
+        }
+
+        if cache_control.trim().is_empty() || cache_control.chars().any(char::is_control) {
+            return Err(BucketError::InvalidCacheControl {
+                reason: "Invalid Cache-Control value.".to_string(),
+            });
         }
     }
+
+    state.heap.cache_control_rules = rules;
+
+    // The headers of every asset may have changed
+    init_certified_assets(state);
+
+    Ok(())
 }
 
-fn commit_chunks(
-    CommitBatch {
-        chunk_ids,
-        batch_id,
-        headers,
-    }: CommitBatch,
-    batch: &Batch,
-    state: &mut State,
-) -> Result<Asset, &'static str> {
-    let now = time();
+//
+// Routing
+//
 
-    if now > batch.expires_at {
-        clear_expired_batches(&mut state.runtime);
-        return Err("Batch did not complete in time. Chunks cannot be committed.");
+pub fn set_routing(routing: RoutingConfig) -> Result<(), BucketError> {
+    STATE.with(|state| set_routing_impl(routing, &mut state.borrow_mut()))
+}
+
+pub fn get_routing() -> RoutingConfig {
+    STATE.with(|state| state.borrow().heap.routing.clone())
+}
+
+fn set_routing_impl(routing: RoutingConfig, state: &mut State) -> Result<(), BucketError> {
+    if let Some(index_file) = &routing.index_file {
+        if index_file.contains('/') || validate_path(&format!("/{index_file}")).is_err() {
+            return Err(BucketError::InvalidRouting {
+                reason: "The index file must be a valid file name.".to_string(),
+            });
+        }
     }
 
-    let mut content_chunks: Vec<Vec<u8>> = vec![];
+    state.heap.routing = routing;
 
-    for chunk_id in &chunk_ids {
-        let chunk = state.runtime.chunks.get(chunk_id);
+    // The urls that serve each asset may have changed
+    init_certified_assets(state);
 
-        match chunk {
-            None => {
-                return Err("Chunk does not exist.");
-            }
-            Some(c) => {
-                if batch_id != c.batch_id {
-                    return Err("Chunk not included in the provided batch.");
-                }
+    Ok(())
+}
 
-                content_chunks.push(c.clone().content);
-            }
+//
+// Quotas and usage
+//
+
+pub fn set_quotas(quotas: Quotas) {
+    STATE.with(|state| state.borrow_mut().heap.quotas = quotas)
+}
+
+pub fn get_usage(caller: Principal) -> Usage {
+    STATE.with(|state| get_usage_impl(&caller, &state.borrow()))
+}
+
+fn get_usage_impl(caller: &Principal, state: &State) -> Usage {
+    Usage {
+        quotas: state.heap.quotas.clone(),
+        bucket_size: state.heap.usage.bucket_size,
+        uploader_size: state.heap.usage.uploader_size(caller),
+        uploader_batches: count_batches(caller, &state.runtime),
+    }
+}
+
+fn check_chunk_quotas(
+    chunk_size: u128,
+    batch_size: u128,
+    quotas: &Quotas,
+) -> Result<(), BucketError> {
+    if let Some(max_size) = quotas.max_chunk_size {
+        if chunk_size > max_size {
+            return Err(BucketError::ChunkTooLarge {
+                size: chunk_size,
+                max_size,
+            });
         }
     }
 
-    if content_chunks.is_empty() {
-        return Err("No chunk to commit.");
+    // The chunks of a batch are the content of a single encoding
+    if let Some(max_size) = quotas.max_asset_size {
+        if batch_size > max_size {
+            return Err(BucketError::AssetTooLarge {
+                size: batch_size,
+                max_size,
+            });
+        }
     }
 
-    let key = batch.clone().key;
+    Ok(())
+}
 
-    // We only use raw at the moment
-    let mut encodings = HashMap::new();
-    encodings.insert(
-        ASSET_ENCODING_KEY_RAW.to_string(),
-        AssetEncoding::try_from(&content_chunks).unwrap(),
-    );
+/// The content replaced by a commit is released once the commit is applied, it still counts
+/// against the quotas when the new content is checked
+fn check_storage_quotas(size: u128, uploader: Principal, state: &State) -> Result<(), BucketError> {
+    let quotas = &state.heap.quotas;
+    let usage = &state.heap.usage;
 
-    let asset: Asset = Asset {
-        key,
-        headers,
-        encodings,
-    };
+    if let Some(max_size) = quotas.max_asset_size {
+        if size > max_size {
+            return Err(BucketError::AssetTooLarge { size, max_size });
+        }
+    }
 
-    state
-        .stable
-        .assets
-        .insert(batch.clone().key.full_path, asset.clone());
+    if let Some(max_size) = quotas.max_bucket_size {
+        if usage.bucket_size.saturating_add(size) > max_size {
+            return Err(BucketError::BucketQuotaExceeded {
+                used: usage.bucket_size,
+                requested: size,
+                max_size,
+            });
+        }
+    }
 
-    clear_batch(batch_id, &chunk_ids, &mut state.runtime);
+    if let Some(max_size) = quotas.max_uploader_size {
+        let used = usage.uploader_size(&uploader);
 
-    Ok(asset)
+        if used.saturating_add(size) > max_size {
+            return Err(BucketError::UploaderQuotaExceeded {
+                uploader,
+                used,
+                requested: size,
+                max_size,
+            });
+        }
+    }
+
+    Ok(())
 }
 
-fn clear_expired_batches(state: &mut RuntimeState) {
+//
+// Upload batch and chunks
+//
+
+const BATCH_EXPIRY_NANOS: u64 = 300_000_000_000;
+
+// Chunks of a batch, more than the stable memory holds at the size of an ingress message
+const MAX_BATCH_CHUNKS: usize = 1_000_000;
+
+pub fn has_batch_secret() -> bool {
+    STATE.with(|state| state.borrow().heap.batch_secret.is_some())
+}
+
+/// Sets the key of the batch ids unless one has been set in the meantime
+pub fn init_batch_secret(secret: Vec<u8>) {
+    STATE.with(|state| {
+        state.borrow_mut().heap.batch_secret.get_or_insert(secret);
+    })
+}
+
+pub fn create_batch(key: AssetKey, caller: Principal) -> Result<u128, BucketError> {
+    STATE.with(|state| create_batch_impl(key, caller, &mut state.borrow_mut()))
+}
+
+pub fn create_chunk(chunk: Chunk, caller: Principal) -> Result<(u128, usize), BucketError> {
+    STATE.with(|state| create_chunk_impl(chunk, caller, &mut state.borrow_mut()))
+}
+
+pub fn get_batch_chunks(batch_id: u128, caller: Principal) -> Result<BatchChunks, BucketError> {
+    STATE.with(|state| get_batch_chunks_impl(batch_id, &caller, &state.borrow().runtime))
+}
+
+pub fn get_pending_batches(caller: Principal) -> Vec<PendingBatch> {
+    STATE.with(|state| get_pending_batches_impl(&caller, &state.borrow().runtime))
+}
+
+pub fn commit_batch(
+    commit_batch: CommitBatch,
+    caller: Principal,
+) -> Result<&'static str, BucketError> {
+    STATE.with(|state| commit_batch_impl(commit_batch, caller, &mut state.borrow_mut()))
+}
+
+fn create_batch_impl(
+    key: AssetKey,
+    caller: Principal,
+    state: &mut State,
+) -> Result<u128, BucketError> {
     let now = time();
+    println!("{key:?}");
 
-    // Remove expired batches
+    let key = validate_key(key)?;
 
-    let batches = state.batches.clone();
+    clear_expired_batches(&mut state.runtime);
 
-    for (batch_id, batch) in &batches {
-        if now > batch.expires_at {
-            state.batches.remove(batch_id);
+    if let Some(max_batches) = state.heap.quotas.max_batches_per_uploader {
+        if count_batches(&caller, &state.runtime) >= max_batches {
+            return Err(BucketError::TooManyBatches { max_batches });
         }
     }
 
-    // Remove chunk without existing batches (those we just deleted above)
+    let batch_id = next_batch_id(&mut state.heap)?;
+    let expires_at = now + BATCH_EXPIRY_NANOS;
 
-    let chunks = state.chunks.clone();
+    state.runtime.batches.insert(
+        batch_id,
+        Batch {
+            key,
+            expires_at,
+            uploader: caller,
+            size: 0,
+            chunks: BTreeMap::new(),
+        },
+    );
+    state.runtime.batch_expiries.insert((expires_at, batch_id));
 
-    for (chunk_id, chunk) in &chunks {
-        if !state.batches.contains_key(&chunk.batch_id) {
-            state.chunks.remove(chunk_id);
-        }
-    }
+    Ok(batch_id)
 }
 
-fn clear_batch(batch_id: u128, chunk_ids: &[u128], state: &mut RuntimeState) {
-    for chunk_id in chunk_ids {
-        state.chunks.remove(chunk_id);
+/// Batch ids are derived from a secret counter, so that an id tells nothing of the others
+fn next_batch_id(state: &mut HeapState) -> Result<u128, BucketError> {
+    let secret = state
+        .batch_secret
+        .as_ref()
+        .ok_or(BucketError::RandomnessUnavailable {
+            reason: "No secret to derive the batch id.".to_string(),
+        })?;
+
+    let batch_count = state.batch_count + 1;
+
+    let batch_id =
+        derive_id(batch_count, secret).map_err(|err| BucketError::RandomnessUnavailable {
+            reason: err.to_string(),
+        })?;
+
+    state.batch_count = batch_count;
+
+    Ok(batch_id)
+}
+
+/// Normalizes the full path of the key, to which its folder and name must match. Both are
+/// derived from the full path when left empty.
+fn validate_key(key: AssetKey) -> Result<AssetKey, BucketError> {
+    let full_path = format!("/{}", key.full_path.trim_start_matches('/'));
+
+    let invalid_key = |reason: String| BucketError::InvalidKey {
+        full_path: full_path.clone(),
+        reason,
+    };
+
+    validate_path(&full_path).map_err(invalid_key)?;
+
+    let folder = parent_folder(&full_path);
+
+    if !key.folder.is_empty() && folder_path(&key.folder) != folder {
+        return Err(invalid_key(format!(
+            "Folder {} does not match the full path.",
+            key.folder
+        )));
+    }
+
+    // The root is the index of the bucket, its name is the one of the uploaded file
+    let name = match full_path.rsplit_once('/') {
+        Some((_, name)) if !name.is_empty() => name.to_string(),
+        _ => key.name.clone(),
+    };
+
+    if !key.name.is_empty() && key.name != name {
+        return Err(invalid_key(format!(
+            "Name {} does not match the full path.",
+            key.name
+        )));
     }
 
-    state.batches.remove(&batch_id);
+    Ok(AssetKey {
+        full_path,
+        folder,
+        name,
+        ..key
+    })
 }
 
-fn update_certified_asset(state: &mut State, asset: &Asset) {
-    // 1. Replace or insert the new asset in tree
-    state.runtime.asset_hashes.insert(asset);
+/// Full paths are absolute, without empty, "." or ".." segments nor characters that would change
+/// the meaning of the url or of the certification paths
+fn validate_path(full_path: &str) -> Result<(), String> {
+    if full_path == "/" {
+        return Ok(());
+    }
 
-    // 2. Update the root hash and the canister certified data
-    update_certified_data(&state.runtime.asset_hashes);
+    for segment in full_path.trim_start_matches('/').split('/') {
+        match segment {
+            "" => return Err("Empty segment in the path.".to_string()),
+            "." | ".." => return Err(format!("Relative segment {segment} in the path.")),
+            _ if segment
+                .chars()
+                .any(|c| c.is_control() || matches!(c, '?' | '#' | '\\' | '<' | '>')) =>
+            {
+                return Err(format!("Invalid character in the segment {segment}."));
+            }
+            _ => (),
+        }
+    }
+
+    Ok(())
 }
 
-fn delete_certified_asset(state: &mut State, full_path: &String) {
-    // 1. Remove the asset in tree
-    state.runtime.asset_hashes.delete(full_path);
+fn create_chunk_impl(
+    Chunk {
+        batch_id,
+        content,
+        index,
+    }: Chunk,
+    caller: Principal,
+    state: &mut State,
+) -> Result<(u128, usize), BucketError> {
+    let now = time();
 
-    // 2. Update the root hash and the canister certified data
-    update_certified_data(&state.runtime.asset_hashes);
+    let (index, size) = match state.runtime.batches.get(&batch_id) {
+        None => return Err(BucketError::BatchNotFound { batch_id }),
+        Some(b) if b.uploader != caller => return Err(BucketError::BatchNotOwned { batch_id }),
+        Some(b) if now > b.expires_at => {
+            return Err(BucketError::BatchExpired {
+                batch_id,
+                expires_at: b.expires_at,
+            })
+        }
+        Some(b) => {
+            let index = match (index, b.chunks.last_key_value()) {
+                (Some(index), _) => Some(index),
+                (None, None) => Some(0),
+                (None, Some((last, _))) => last.checked_add(1),
+            };
+
+            let index = match index {
+                Some(index) if index < MAX_BATCH_CHUNKS => index,
+                _ => {
+                    return Err(BucketError::TooManyChunks {
+                        batch_id,
+                        max_chunks: MAX_BATCH_CHUNKS,
+                    })
+                }
+            };
+
+            let replaced = b
+                .chunks
+                .get(&index)
+                .and_then(|chunk_id| state.runtime.chunks.get(chunk_id))
+                .map_or(0, |chunk| chunk.content.len() as u128);
+
+            (
+                index,
+                b.size
+                    .saturating_sub(replaced)
+                    .saturating_add(content.len() as u128),
+            )
+        }
+    };
+
+    check_chunk_quotas(content.len() as u128, size, &state.heap.quotas)?;
+
+    state.heap.chunk_count += 1;
+
+    let chunk_id = state.heap.chunk_count;
+    let runtime = &mut state.runtime;
+
+    // Each chunk extends the life of its batch
+    if let Some(batch) = runtime.batches.get_mut(&batch_id) {
+        runtime.batch_expiries.remove(&(batch.expires_at, batch_id));
+
+        batch.expires_at = now + BATCH_EXPIRY_NANOS;
+        batch.size = size;
+
+        if let Some(replaced) = batch.chunks.insert(index, chunk_id) {
+            runtime.chunks.remove(&replaced);
+        }
+
+        runtime.batch_expiries.insert((batch.expires_at, batch_id));
+    }
+
+    runtime.chunks.insert(
+        chunk_id,
+        UploadedChunk {
+            batch_id,
+            content,
+            uploader: caller,
+        },
+    );
+
+    Ok((chunk_id, index))
+}
+
+fn get_batch_chunks_impl(
+    batch_id: u128,
+    caller: &Principal,
+    state: &RuntimeState,
+) -> Result<BatchChunks, BucketError> {
+    match state.batches.get(&batch_id) {
+        None => Err(BucketError::BatchNotFound { batch_id }),
+        Some(b) if &b.uploader != caller => Err(BucketError::BatchNotOwned { batch_id }),
+        Some(b) if time() > b.expires_at => Err(BucketError::BatchExpired {
+            batch_id,
+            expires_at: b.expires_at,
+        }),
+        Some(b) => Ok(BatchChunks {
+            batch_id,
+            indices: b.chunks.keys().copied().collect(),
+            size: b.size,
+            expires_at: b.expires_at,
+        }),
+    }
+}
+
+fn get_pending_batches_impl(caller: &Principal, state: &RuntimeState) -> Vec<PendingBatch> {
+    let now = time();
+
+    state
+        .batches
+        .iter()
+        .filter(|(_, batch)| &batch.uploader == caller && now <= batch.expires_at)
+        .map(|(batch_id, batch)| PendingBatch {
+            batch_id: *batch_id,
+            full_path: batch.key.full_path.clone(),
+            expires_at: batch.expires_at,
+            size: batch.size,
+        })
+        .collect()
+}
+
+fn commit_batch_impl(
+    commit_batch: CommitBatch,
+    caller: Principal,
+    state: &mut State,
+) -> Result<&'static str, BucketError> {
+    let batch = state.runtime.batches.get(&commit_batch.batch_id).cloned();
+
+    match batch {
+        None => Err(BucketError::BatchNotFound {
+            batch_id: commit_batch.batch_id,
+        }),
+        Some(b) => {
+            let previous = state.stable.assets.get(&b.key.full_path);
+            let asset = commit_chunks(commit_batch, &b, previous.as_ref(), caller, state);
+            match asset {
+                Err(err) => Err(err),
+                Ok(asset) => {
+                    let asset = publish_asset(asset, caller, state);
+                    update_certified_asset(state, &asset);
+                    Ok("Batch committed and certified assets updated.")
+                }
+            }
+        }
+    }
+}
+
+/// Builds the asset of the batch on top of the `previous` one and writes its content in stable
+/// memory. The caller is in charge of storing the asset and of releasing the replaced content.
+fn commit_chunks(
+    CommitBatch {
+        chunk_ids,
+        batch_id,
+        headers,
+        encoding_type,
+        sha256,
+        total_length,
+    }: CommitBatch,
+    batch: &Batch,
+    previous: Option<&Asset>,
+    caller: Principal,
+    state: &mut State,
+) -> Result<Asset, BucketError> {
+    let now = time();
+
+    if batch.uploader != caller {
+        return Err(BucketError::BatchNotOwned { batch_id });
+    }
+
+    if now > batch.expires_at {
+        clear_expired_batches(&mut state.runtime);
+        return Err(BucketError::BatchExpired {
+            batch_id,
+            expires_at: batch.expires_at,
+        });
+    }
+
+    let encoding_type = encoding_key(encoding_type)?;
+
+    let chunk_ids = if chunk_ids.is_empty() {
+        indexed_chunk_ids(batch_id, batch)?
+    } else {
+        chunk_ids
+    };
+
+    let mut content_chunks: Vec<Vec<u8>> = vec![];
+
+    for chunk_id in &chunk_ids {
+        let chunk = state.runtime.chunks.get(chunk_id);
+
+        match chunk {
+            None => {
+                return Err(BucketError::ChunkNotFound {
+                    chunk_id: *chunk_id,
+                });
+            }
+            Some(c) => {
+                if batch_id != c.batch_id {
+                    return Err(BucketError::ChunkNotInBatch {
+                        chunk_id: *chunk_id,
+                        batch_id,
+                    });
+                }
+
+                if c.uploader != caller {
+                    return Err(BucketError::BatchNotOwned { batch_id });
+                }
+
+                content_chunks.push(c.clone().content);
+            }
+        }
+    }
+
+    if content_chunks.is_empty() {
+        return Err(BucketError::EmptyBatch { batch_id });
+    }
+
+    let key = batch.clone().key;
+
+    let encoding =
+        AssetEncoding::try_from(&content_chunks).map_err(|err| BucketError::InvalidContent {
+            reason: err.to_string(),
+        })?;
+
+    // Chunks missing or in the wrong order
+    if let Some(expected) = total_length {
+        if expected != encoding.total_length {
+            return Err(BucketError::LengthMismatch {
+                expected,
+                actual: encoding.total_length,
+            });
+        }
+    }
+
+    if let Some(expected) = sha256 {
+        if expected.as_slice() != encoding.sha256 {
+            return Err(BucketError::Sha256Mismatch {
+                expected,
+                actual: ByteBuf::from(encoding.sha256),
+            });
+        }
+    }
+
+    check_storage_quotas(encoding.total_length, caller, state)?;
+
+    let is_raw = encoding_type == ASSET_ENCODING_KEY_RAW;
+
+    // The bucket, not the client, tells when and how much was uploaded. An encoded variant leaves
+    // the key of its raw commit unchanged. The size of a key is 32 bits, a larger raw content is
+    // refused rather than listed with a wrong size.
+    let key = match previous {
+        Some(previous) if !is_raw => previous.key.clone(),
+        _ => AssetKey {
+            created: now,
+            size: u32::try_from(encoding.total_length).map_err(|_| BucketError::AssetTooLarge {
+                size: encoding.total_length,
+                max_size: u128::from(u32::MAX),
+            })?,
+            ..key
+        },
+    };
+
+    // Encoded variants belong to the raw content they were compressed from. A raw content that
+    // changed outdates them, an encoded variant keeps the headers of the raw commit.
+    let (mut encodings, headers) = match previous {
+        Some(previous) if !is_raw => (previous.encodings.clone(), previous.headers.clone()),
+        Some(previous) if previous.encoding_raw().sha256 == encoding.sha256 => {
+            (previous.encodings.clone(), headers)
+        }
+        _ => (HashMap::new(), headers),
+    };
+
+    if !is_raw && !encodings.contains_key(ASSET_ENCODING_KEY_RAW) {
+        return Err(BucketError::RawEncodingMissing {
+            full_path: key.full_path,
+        });
+    }
+
+    let headers = if is_raw {
+        with_content_type(headers, &key, &content_chunks[0])
+    } else {
+        headers
+    };
+
+    encodings.insert(
+        encoding_type,
+        AssetEncoding {
+            content_chunks: insert_content_chunks(content_chunks, Some(caller), state),
+            uploaded_by: Some(caller),
+            ..encoding
+        },
+    );
+
+    let asset: Asset = Asset {
+        key,
+        headers,
+        encodings,
+        commit: match previous {
+            Some(previous) if !is_raw => previous.commit.clone(),
+            _ => None,
+        },
+    };
+
+    remove_batch(batch_id, &mut state.runtime);
+
+    Ok(asset)
+}
+
+/// Ids of the chunks of the batch in the order of their index, which must leave no gap
+fn indexed_chunk_ids(batch_id: u128, batch: &Batch) -> Result<Vec<u128>, BucketError> {
+    batch
+        .chunks
+        .iter()
+        .enumerate()
+        .map(|(expected, (index, chunk_id))| {
+            if *index == expected {
+                Ok(*chunk_id)
+            } else {
+                Err(BucketError::ChunkIndexMissing {
+                    batch_id,
+                    index: expected,
+                })
+            }
+        })
+        .collect()
+}
+
+fn encoding_key(encoding_type: Option<String>) -> Result<String, BucketError> {
+    match encoding_type.as_deref() {
+        None | Some("identity") => Ok(ASSET_ENCODING_KEY_RAW.to_string()),
+        Some(encoding_type) if ASSET_ENCODING_KEYS_PREFERENCE.contains(&encoding_type) => {
+            Ok(encoding_type.to_string())
+        }
+        Some(encoding_type) => Err(BucketError::UnsupportedEncoding {
+            encoding_type: encoding_type.to_string(),
+        }),
+    }
+}
+
+/// Removes the batches past their expiry with their chunks, found in the expiry index without
+/// visiting the batches still open
+fn clear_expired_batches(state: &mut RuntimeState) {
+    let now = time();
+
+    let expired: Vec<u128> = state
+        .batch_expiries
+        .range(..(now, 0))
+        .map(|(_, batch_id)| *batch_id)
+        .collect();
+
+    for batch_id in expired {
+        if let Some(batch) = remove_batch(batch_id, state) {
+            state.sweep_stats.expired_batches += 1;
+            state.sweep_stats.expired_chunks += batch.chunks.len() as u64;
+        }
+    }
+}
+
+fn remove_batch(batch_id: u128, state: &mut RuntimeState) -> Option<Batch> {
+    let batch = state.batches.remove(&batch_id)?;
+
+    state.batch_expiries.remove(&(batch.expires_at, batch_id));
+
+    for chunk_id in batch.chunks.values() {
+        state.chunks.remove(chunk_id);
+    }
+
+    Some(batch)
+}
+
+fn count_batches(uploader: &Principal, state: &RuntimeState) -> u32 {
+    let count = state
+        .batches
+        .values()
+        .filter(|batch| &batch.uploader == uploader)
+        .count();
+
+    u32::try_from(count).unwrap_or(u32::MAX)
+}
+
+//
+// Garbage collection of the expired batches
+//
+
+pub fn sweep_expired_batches() -> GcStats {
+    STATE.with(|state| {
+        let runtime = &mut state.borrow_mut().runtime;
+
+        clear_expired_batches(runtime);
+
+        runtime.sweep_stats.sweeps += 1;
+        runtime.sweep_stats.last_sweep_at = Some(time());
+
+        get_gc_stats_impl(runtime)
+    })
+}
+
+pub fn get_gc_stats() -> GcStats {
+    STATE.with(|state| get_gc_stats_impl(&state.borrow().runtime))
+}
+
+fn get_gc_stats_impl(state: &RuntimeState) -> GcStats {
+    GcStats {
+        pending_batches: state.batches.len() as u64,
+        pending_chunks: state.chunks.len() as u64,
+        next_expires_at: state
+            .batch_expiries
+            .first()
+            .map(|(expires_at, _)| *expires_at),
+        sweeps: state.sweep_stats.sweeps,
+        last_sweep_at: state.sweep_stats.last_sweep_at,
+        expired_batches: state.sweep_stats.expired_batches,
+        expired_chunks: state.sweep_stats.expired_chunks,
+    }
+}
+
+fn update_certified_asset(state: &mut State, asset: &Asset) {
+    // 1. Replace or insert the new asset in tree
+    certify_urls(&asset.key.full_path, state);
+
+    // 2. Update the root hash and the canister certified data, with the fallback responses if
+    // the asset is the fallback
+    if state.heap.fallback.as_ref() == Some(&asset.key.full_path) {
+        update_certified_fallback(state);
+    } else {
+        update_certified_data(&state.runtime.asset_hashes);
+    }
+}
+
+fn delete_certified_asset(state: &mut State, full_path: &str) {
+    // 1. Remove the asset in tree
+    certify_urls(full_path, state);
+
+    // 2. Update the root hash and the canister certified data, with the fallback responses if
+    // the asset was the fallback
+    if state.heap.fallback.as_deref() == Some(full_path) {
+        update_certified_fallback(state);
+    } else {
+        update_certified_data(&state.runtime.asset_hashes);
+    }
+}
+
+// Certifies again the urls that resolve to the full path, or resolved to it before a change,
+// with the asset they now resolve to
+fn certify_urls(full_path: &str, state: &mut State) {
+    for url in alias_urls(full_path, &state.heap.routing) {
+        match resolve_url(&url, &state.heap.routing, &state.stable.assets) {
+            None => state.runtime.asset_hashes.delete(&url),
+            Some(asset) => {
+                state
+                    .runtime
+                    .asset_hashes
+                    .insert(&url, &asset, &state.heap.cache_control_rules)
+            }
+        }
+    }
+}
+
+fn update_certified_fallback(state: &mut State) {
+    let fallback = get_fallback_asset_impl(state);
+
+    state.runtime.asset_hashes.insert_fallback(
+        fallback.as_ref(),
+        fallback_status(&state.heap.routing),
+        &state.heap.cache_control_rules,
+    );
+
+    update_certified_data(&state.runtime.asset_hashes);
+}
+
+/// Certifies the assets in stable memory and the responses to the urls that match none of them
+pub fn init_certified_assets(state: &mut State) {
+    state.runtime.asset_hashes = AssetHashes::from_assets(
+        &state.stable.assets,
+        &state.heap.routing,
+        &state.heap.cache_control_rules,
+    );
+
+    update_certified_fallback(state);
+}
+
+//
+// Deploys
+//
+
+// Time left to stage the next change of a deploy before it is aborted
+const DEPLOY_EXPIRY_NANOS: u64 = 3_600_000_000_000;
+
+pub fn create_deploy(caller: Principal) -> u128 {
+    STATE.with(|state| create_deploy_impl(caller, &mut state.borrow_mut()))
+}
+
+pub fn stage_batch(
+    deploy_id: u128,
+    commit_batch: CommitBatch,
+    caller: Principal,
+) -> Result<(), BucketError> {
+    STATE.with(|state| stage_batch_impl(deploy_id, commit_batch, caller, &mut state.borrow_mut()))
+}
+
+pub fn stage_deletion(deploy_id: u128, param: Del, caller: Principal) -> Result<(), BucketError> {
+    STATE.with(|state| stage_deletion_impl(deploy_id, param, caller, &mut state.borrow_mut()))
+}
+
+pub fn commit_deploy(deploy_id: u128, caller: Principal) -> Result<(), BucketError> {
+    STATE.with(|state| commit_deploy_impl(deploy_id, caller, &mut state.borrow_mut()))
+}
+
+pub fn abort_deploy(deploy_id: u128, caller: Principal) -> Result<(), BucketError> {
+    STATE.with(|state| abort_deploy_impl(deploy_id, caller, &mut state.borrow_mut()))
+}
+
+fn create_deploy_impl(caller: Principal, state: &mut State) -> u128 {
+    clear_expired_deploys(state);
+
+    // The count is kept over upgrades, an id is never handed out twice
+    state.heap.deploy_count += 1;
+
+    let deploy_id = state.heap.deploy_count;
+
+    state.heap.deploys.insert(
+        deploy_id,
+        Deploy {
+            assets: HashMap::new(),
+            deletions: Vec::new(),
+            expires_at: time() + DEPLOY_EXPIRY_NANOS,
+            deployer: caller,
+        },
+    );
+
+    deploy_id
+}
+
+fn stage_batch_impl(
+    deploy_id: u128,
+    commit_batch: CommitBatch,
+    caller: Principal,
+    state: &mut State,
+) -> Result<(), BucketError> {
+    check_deploy(deploy_id, caller, state)?;
+
+    let batch = state
+        .runtime
+        .batches
+        .get(&commit_batch.batch_id)
+        .cloned()
+        .ok_or(BucketError::BatchNotFound {
+            batch_id: commit_batch.batch_id,
+        })?;
+
+    // Staged assets build on the content staged in the same deploy only, so that committing or
+    // aborting the deploy never releases the content of a live asset
+    let previous = state
+        .heap
+        .deploys
+        .get(&deploy_id)
+        .and_then(|deploy| deploy.assets.get(&batch.key.full_path).cloned());
+
+    let asset = commit_chunks(commit_batch, &batch, previous.as_ref(), caller, state)?;
+
+    if let Some(previous) = previous {
+        delete_replaced_content_chunks(&previous, &asset, state);
+    }
+
+    if let Some(deploy) = state.heap.deploys.get_mut(&deploy_id) {
+        deploy
+            .deletions
+            .retain(|full_path| full_path != &asset.key.full_path);
+        deploy.assets.insert(asset.key.full_path.clone(), asset);
+        deploy.expires_at = time() + DEPLOY_EXPIRY_NANOS;
+    }
+
+    Ok(())
+}
+
+fn stage_deletion_impl(
+    deploy_id: u128,
+    Del { full_path, token }: Del,
+    caller: Principal,
+    state: &mut State,
+) -> Result<(), BucketError> {
+    check_deploy(deploy_id, caller, state)?;
+
+    let live = match get_asset_impl(&full_path, token, state) {
+        Err(GetAssetError::Forbidden(_)) => return Err(BucketError::InvalidToken { full_path }),
+        Err(GetAssetError::BadRequest(_) | GetAssetError::NotFound(_)) => false,
+        Ok(_) => true,
+    };
+
+    let deploy = state
+        .heap
+        .deploys
+        .get_mut(&deploy_id)
+        .ok_or(BucketError::DeployNotFound { deploy_id })?;
+
+    // The last change staged for a path wins
+    let staged = deploy.assets.remove(&full_path);
+
+    if !live && staged.is_none() {
+        return Err(BucketError::AssetNotFound { full_path });
+    }
+
+    if live && !deploy.deletions.contains(&full_path) {
+        deploy.deletions.push(full_path);
+    }
+
+    deploy.expires_at = time() + DEPLOY_EXPIRY_NANOS;
+
+    if let Some(staged) = staged {
+        delete_content_chunks(&staged, state);
+    }
+
+    Ok(())
+}
+
+/// Applies the deletions and the assets of the deploy, then updates the certified data once so
+/// that no response mixes the former and the new assets.
+fn commit_deploy_impl(
+    deploy_id: u128,
+    caller: Principal,
+    state: &mut State,
+) -> Result<(), BucketError> {
+    check_deploy(deploy_id, caller, state)?;
+
+    let Deploy {
+        assets, deletions, ..
+    } = state
+        .heap
+        .deploys
+        .remove(&deploy_id)
+        .ok_or(BucketError::DeployNotFound { deploy_id })?;
+
+    for full_path in &deletions {
+        remove_asset(full_path, state);
+        certify_urls(full_path, state);
+    }
+
+    for asset in assets.into_values() {
+        let asset = publish_asset(asset, caller, state);
+        certify_urls(&asset.key.full_path, state);
+    }
+
+    update_certified_fallback(state);
+
+    Ok(())
+}
+
+fn abort_deploy_impl(
+    deploy_id: u128,
+    caller: Principal,
+    state: &mut State,
+) -> Result<(), BucketError> {
+    check_deployer(deploy_id, caller, state)?;
+
+    let deploy = state
+        .heap
+        .deploys
+        .remove(&deploy_id)
+        .ok_or(BucketError::DeployNotFound { deploy_id })?;
+
+    delete_staged_content(&deploy, state);
+
+    Ok(())
+}
+
+/// Fails if the deploy does not exist, was created by another principal or has expired, in which
+/// case its content is released
+fn check_deploy(deploy_id: u128, caller: Principal, state: &mut State) -> Result<(), BucketError> {
+    let expires_at = check_deployer(deploy_id, caller, state)?;
+
+    if time() > expires_at {
+        if let Some(deploy) = state.heap.deploys.remove(&deploy_id) {
+            delete_staged_content(&deploy, state);
+        }
+        return Err(BucketError::DeployExpired {
+            deploy_id,
+            expires_at,
+        });
+    }
+
+    Ok(())
+}
+
+/// Returns the expiry of the deploy if it was created by the caller
+fn check_deployer(deploy_id: u128, caller: Principal, state: &State) -> Result<u64, BucketError> {
+    match state.heap.deploys.get(&deploy_id) {
+        None => Err(BucketError::DeployNotFound { deploy_id }),
+        Some(deploy) if deploy.deployer != caller => Err(BucketError::DeployNotOwned { deploy_id }),
+        Some(deploy) => Ok(deploy.expires_at),
+    }
+}
+
+fn clear_expired_deploys(state: &mut State) {
+    let now = time();
+
+    let expired: Vec<u128> = state
+        .heap
+        .deploys
+        .iter()
+        .filter(|(_, deploy)| now > deploy.expires_at)
+        .map(|(deploy_id, _)| *deploy_id)
+        .collect();
+
+    for deploy_id in expired {
+        if let Some(deploy) = state.heap.deploys.remove(&deploy_id) {
+            delete_staged_content(&deploy, state);
+        }
+    }
+}
+
+fn delete_staged_content(deploy: &Deploy, state: &mut State) {
+    for asset in deploy.assets.values() {
+        delete_content_chunks(asset, state);
+    }
+}
+
+//
+// Revisions
+//
+
+// Previous versions kept per full path
+const MAX_REVISIONS: usize = 10;
+
+pub fn get_revisions(full_path: &str) -> Vec<AssetRevision> {
+    STATE.with(|state| get_revisions_impl(full_path, &state.borrow().stable))
+}
+
+pub fn get_revision_chunk(param: GetRevisionChunk) -> Result<Vec<u8>, BucketError> {
+    STATE.with(|state| get_revision_chunk_impl(param, &state.borrow().stable))
+}
+
+pub fn rollback_asset(full_path: String, revision: u64) -> Result<(), BucketError> {
+    STATE.with(|state| rollback_asset_impl(full_path, revision, &mut state.borrow_mut()))
+}
+
+fn get_revisions_impl(full_path: &str, state: &StableState) -> Vec<AssetRevision> {
+    let revisions = state
+        .revisions
+        .get(&full_path.to_string())
+        .unwrap_or_default();
+
+    revisions
+        .assets
+        .iter()
+        .map(|asset| {
+            let encoding = asset.encoding_raw();
+
+            AssetRevision {
+                revision: revision_number(asset),
+                committed_at: asset.commit.as_ref().map(|commit| commit.committed_at),
+                committed_by: asset.commit.as_ref().map(|commit| commit.committed_by),
+                headers: asset.headers.clone(),
+                sha256: ByteBuf::from(encoding.sha256),
+                total_length: encoding.total_length,
+                chunks_count: encoding.content_chunks.len(),
+            }
+        })
+        .collect()
+}
+
+fn get_revision_chunk_impl(
+    GetRevisionChunk {
+        full_path,
+        revision,
+        index,
+    }: GetRevisionChunk,
+    state: &StableState,
+) -> Result<Vec<u8>, BucketError> {
+    let revisions = state.revisions.get(&full_path).unwrap_or_default();
+
+    let asset = revisions
+        .assets
+        .iter()
+        .find(|asset| revision_number(asset) == revision)
+        .ok_or(BucketError::RevisionNotFound {
+            full_path,
+            revision,
+        })?;
+
+    let encoding = asset.encoding_raw();
+
+    get_content_chunk_impl(encoding, index, state).map_err(|_| BucketError::ChunkIndexOutOfRange {
+        index,
+        chunks_count: encoding.content_chunks.len(),
+    })
+}
+
+/// Makes the revision live again. The live asset it replaces becomes a revision in its stead.
+fn rollback_asset_impl(
+    full_path: String,
+    revision: u64,
+    state: &mut State,
+) -> Result<(), BucketError> {
+    let live = state
+        .stable
+        .assets
+        .get(&full_path)
+        .ok_or(BucketError::AssetNotFound {
+            full_path: full_path.clone(),
+        })?;
+
+    let mut revisions = state.stable.revisions.get(&full_path).unwrap_or_default();
+
+    let position = revisions
+        .assets
+        .iter()
+        .position(|asset| revision_number(asset) == revision)
+        .ok_or(BucketError::RevisionNotFound {
+            full_path: full_path.clone(),
+            revision,
+        })?;
+
+    let asset = revisions.assets.remove(position);
+    revisions.assets.push(live);
+
+    state.stable.assets.insert(full_path.clone(), asset.clone());
+    state.stable.revisions.insert(full_path, revisions);
+
+    update_certified_asset(state, &asset);
+
+    Ok(())
+}
+
+/// Makes the asset live. The asset it replaces is kept as a revision when the raw content is new,
+/// otherwise an encoded variant was added and the asset remains the same revision.
+fn publish_asset(mut asset: Asset, caller: Principal, state: &mut State) -> Asset {
+    let full_path = asset.key.full_path.clone();
+    let previous = state.stable.assets.get(&full_path);
+    let mut revisions = state.stable.revisions.get(&full_path).unwrap_or_default();
+
+    let new_revision = previous.as_ref().is_none_or(|previous| {
+        previous.encoding_raw().content_chunks != asset.encoding_raw().content_chunks
+    });
+
+    let released = if new_revision {
+        asset.commit = Some(AssetCommit {
+            revision: next_revision(previous.as_ref(), &revisions),
+            committed_at: time(),
+            committed_by: caller,
+        });
+
+        revisions.assets.extend(previous);
+
+        (revisions.assets.len() > MAX_REVISIONS).then(|| revisions.assets.remove(0))
+    } else {
+        previous
+    };
+
+    state.stable.assets.insert(full_path.clone(), asset.clone());
+
+    if !revisions.assets.is_empty() {
+        state.stable.revisions.insert(full_path, revisions);
+    }
+
+    if let Some(released) = released {
+        release_content_chunks(&released, state);
+    }
+
+    asset
+}
+
+/// Removes the live asset and its revisions with their content
+fn remove_asset(full_path: &str, state: &mut State) {
+    let asset = state.stable.assets.remove(&full_path.to_string());
+    let revisions = state
+        .stable
+        .revisions
+        .remove(&full_path.to_string())
+        .unwrap_or_default();
+
+    for asset in asset.iter().chain(&revisions.assets) {
+        delete_content_chunks(asset, state);
+    }
+}
+
+fn revision_number(asset: &Asset) -> u64 {
+    asset.commit.as_ref().map_or(0, |commit| commit.revision)
+}
+
+fn next_revision(previous: Option<&Asset>, revisions: &AssetRevisions) -> u64 {
+    previous
+        .into_iter()
+        .chain(&revisions.assets)
+        .map(revision_number)
+        .max()
+        .map_or(1, |revision| revision + 1)
+}
+
+//
+// Folders
+//
+
+pub fn list_folder(path: &str) -> FolderListing {
+    STATE.with(|state| list_folder_impl(path, &state.borrow().stable))
+}
+
+pub fn get_folder_stats(path: &str) -> FolderStats {
+    STATE.with(|state| get_folder_stats_impl(path, &state.borrow().stable))
+}
+
+pub fn move_folder(from: &str, to: &str) -> Result<(), BucketError> {
+    STATE.with(|state| move_folder_impl(from, to, &mut state.borrow_mut()))
+}
+
+fn list_folder_impl(path: &str, state: &StableState) -> FolderListing {
+    let prefix = folder_prefix(&folder_path(path));
+
+    let mut files: Vec<AssetKey> = Vec::new();
+    let mut folders: BTreeMap<String, FolderStats> = BTreeMap::new();
+
+    for (full_path, asset) in folder_assets(&prefix, state) {
+        match full_path[prefix.len()..].split_once('/') {
+            None => files.push(asset.key),
+            Some((name, _)) => {
+                let path = format!("{prefix}{name}");
+
+                let stats = folders.entry(path.clone()).or_insert(FolderStats {
+                    path,
+                    size: 0,
+                    count: 0,
+                });
+
+                stats.size += asset.encoding_raw().total_length;
+                stats.count += 1;
+            }
+        }
+    }
+
+    FolderListing {
+        files,
+        folders: folders.into_values().collect(),
+    }
+}
+
+fn get_folder_stats_impl(path: &str, state: &StableState) -> FolderStats {
+    let path = folder_path(path);
+
+    folder_assets(&folder_prefix(&path), state).fold(
+        FolderStats {
+            path,
+            size: 0,
+            count: 0,
+        },
+        |stats, (_, asset)| FolderStats {
+            size: stats.size + asset.encoding_raw().total_length,
+            count: stats.count + 1,
+            ..stats
+        },
+    )
+}
+
+/// Moves every asset under the folder, with its revisions, and certifies them at their new path
+fn move_folder_impl(from: &str, to: &str, state: &mut State) -> Result<(), BucketError> {
+    let from = folder_path(from);
+    let to = folder_path(to);
+
+    let from_prefix = folder_prefix(&from);
+    let to_prefix = folder_prefix(&to);
+
+    if from == "/" || to == from || to_prefix.starts_with(&from_prefix) {
+        return Err(BucketError::InvalidMove {
+            reason: format!("{from} cannot be moved to {to}."),
+        });
+    }
+
+    validate_path(&to).map_err(|reason| BucketError::InvalidMove { reason })?;
+
+    let full_paths: Vec<String> = folder_assets(&from_prefix, &state.stable)
+        .map(|(full_path, _)| full_path)
+        .collect();
+
+    if full_paths.is_empty() {
+        return Err(BucketError::FolderNotFound { path: from });
+    }
+
+    // An asset cannot be replaced by a move, nor a file share its path with a folder. The root is
+    // a folder, the asset at "/" is the index of the bucket.
+    let destinations: Vec<String> = full_paths
+        .iter()
+        .map(|full_path| format!("{to_prefix}{}", &full_path[from_prefix.len()..]))
+        .collect();
+
+    if let Some(full_path) = destinations
+        .iter()
+        .chain(Some(&to).filter(|to| to.as_str() != "/"))
+        .find(|full_path| state.stable.assets.contains_key(full_path))
+    {
+        return Err(BucketError::DestinationExists {
+            full_path: full_path.clone(),
+        });
+    }
+
+    for (full_path, destination) in full_paths.iter().zip(destinations) {
+        if let Some(asset) = state.stable.assets.remove(full_path) {
+            let asset = moved_asset(asset, &destination);
+
+            state.stable.assets.insert(destination.clone(), asset);
+
+            certify_urls(full_path, state);
+            certify_urls(&destination, state);
+        }
+
+        if let Some(revisions) = state.stable.revisions.remove(full_path) {
+            let assets = revisions
+                .assets
+                .into_iter()
+                .map(|asset| moved_asset(asset, &destination))
+                .collect();
+
+            state
+                .stable
+                .revisions
+                .insert(destination.clone(), AssetRevisions { assets });
+        }
+
+        if state.heap.fallback.as_ref() == Some(full_path) {
+            state.heap.fallback = Some(destination);
+        }
+    }
+
+    update_certified_fallback(state);
+
+    Ok(())
+}
+
+fn folder_assets<'a>(
+    prefix: &'a str,
+    state: &'a StableState,
+) -> impl Iterator<Item = (String, Asset)> + 'a {
+    state
+        .assets
+        .range(prefix.to_string()..)
+        .take_while(move |(full_path, _)| full_path.starts_with(prefix))
+}
+
+fn moved_asset(asset: Asset, full_path: &str) -> Asset {
+    Asset {
+        key: AssetKey {
+            full_path: full_path.to_string(),
+            folder: parent_folder(full_path),
+            ..asset.key
+        },
+        ..asset
+    }
+}
+
+/// Folder of an asset: the path of its parent, "/" at the root
+fn parent_folder(full_path: &str) -> String {
+    match full_path.rsplit_once('/') {
+        None | Some(("", _)) => "/".to_string(),
+        Some((folder, _)) => folder.to_string(),
+    }
+}
+
+// "/images/" and "images" are both the folder "/images"
+fn folder_path(path: &str) -> String {
+    format!("/{}", path.trim_matches('/'))
+}
+
+// Prefix of the full paths of the assets under the folder
+fn folder_prefix(path: &str) -> String {
+    match path {
+        "/" => path.to_string(),
+        _ => format!("{path}/"),
+    }
+}
+
+//
+// Content chunks in stable memory
+//
+
+pub fn insert_content_chunks(
+    content_chunks: Vec<Vec<u8>>,
+    uploader: Option<Principal>,
+    state: &mut State,
+) -> Vec<u64> {
+    let mut next_chunk_id = state
+        .stable
+        .content_chunks
+        .last_key_value()
+        .map_or(0, |(chunk_id, _)| chunk_id + 1);
+
+    content_chunks
+        .into_iter()
+        .map(|content| {
+            let chunk_id = next_chunk_id;
+            state.heap.usage.add(uploader, content.len() as u128);
+            state.stable.content_chunks.insert(chunk_id, content);
+            next_chunk_id += 1;
+            chunk_id
+        })
+        .collect()
+}
+
+fn delete_replaced_content_chunks(previous: &Asset, asset: &Asset, state: &mut State) {
+    let kept: HashSet<&u64> = asset
+        .encodings
+        .values()
+        .flat_map(|encoding| &encoding.content_chunks)
+        .collect();
+
+    for encoding in previous.encodings.values() {
+        for chunk_id in &encoding.content_chunks {
+            if !kept.contains(chunk_id) {
+                remove_content_chunk(chunk_id, encoding, state);
+            }
+        }
+    }
+}
+
+// Revisions of a path share the chunks of the encodings they did not replace, the content of a
+// replaced asset is deleted only once no version of its path references it
+fn release_content_chunks(released: &Asset, state: &mut State) {
+    let full_path = &released.key.full_path;
+    let live = state.stable.assets.get(full_path);
+    let revisions = state.stable.revisions.get(full_path).unwrap_or_default();
+
+    let kept: HashSet<u64> = live
+        .iter()
+        .chain(&revisions.assets)
+        .flat_map(|asset| asset.encodings.values())
+        .flat_map(|encoding| encoding.content_chunks.iter().copied())
+        .collect();
+
+    for encoding in released.encodings.values() {
+        for chunk_id in &encoding.content_chunks {
+            if !kept.contains(chunk_id) {
+                remove_content_chunk(chunk_id, encoding, state);
+            }
+        }
+    }
+}
+
+fn delete_content_chunks(asset: &Asset, state: &mut State) {
+    for encoding in asset.encodings.values() {
+        for chunk_id in &encoding.content_chunks {
+            remove_content_chunk(chunk_id, encoding, state);
+        }
+    }
+}
+
+// Chunks shared by several versions of an asset may be released more than once, only the first
+// removal counts
+fn remove_content_chunk(chunk_id: &u64, encoding: &AssetEncoding, state: &mut State) {
+    if let Some(content) = state.stable.content_chunks.remove(chunk_id) {
+        state
+            .heap
+            .usage
+            .remove(encoding.uploaded_by, content.len() as u128);
+    }
 }
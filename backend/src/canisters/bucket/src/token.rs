@@ -0,0 +1,64 @@
+use base64::{engine::general_purpose, Engine};
+use candid::{Decode, Encode};
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::types::store::TokenClaims;
+
+type HmacSha256 = Hmac<Sha256>;
+
+//
+// Signed access tokens: the candid encoded claims and their HMAC-SHA256, both base64url encoded
+// and joined with a dot so that the token can be used as is in a url.
+//
+
+pub fn sign_token(claims: &TokenClaims, secret: &[u8]) -> Result<String, &'static str> {
+    let payload = Encode!(claims).map_err(|_| "Failed to encode the token.")?;
+    let signature = hmac(secret)?.chain_update(&payload).finalize().into_bytes();
+
+    Ok(format!(
+        "{}.{}",
+        general_purpose::URL_SAFE_NO_PAD.encode(&payload),
+        general_purpose::URL_SAFE_NO_PAD.encode(signature)
+    ))
+}
+
+/// Claims of a token signed with the secret. Whether they grant access is up to the caller.
+pub fn verify_token(token: &str, secret: &[u8]) -> Result<TokenClaims, &'static str> {
+    let (payload, signature) = token.split_once('.').ok_or("Malformed token.")?;
+
+    let payload = general_purpose::URL_SAFE_NO_PAD
+        .decode(payload)
+        .map_err(|_| "Malformed token.")?;
+    let signature = general_purpose::URL_SAFE_NO_PAD
+        .decode(signature)
+        .map_err(|_| "Malformed token.")?;
+
+    // Compared in constant time
+    hmac(secret)?
+        .chain_update(&payload)
+        .verify_slice(&signature)
+        .map_err(|_| "Invalid token.")?;
+
+    Decode!(&payload, TokenClaims).map_err(|_| "Malformed token.")
+}
+
+//
+// Opaque ids: the HMAC-SHA256 of a counter, truncated to 128 bits.
+//
+
+pub fn derive_id(counter: u128, secret: &[u8]) -> Result<u128, &'static str> {
+    let digest = hmac(secret)?
+        .chain_update(counter.to_be_bytes())
+        .finalize()
+        .into_bytes();
+
+    let mut id = [0u8; 16];
+    id.copy_from_slice(&digest[..16]);
+
+    Ok(u128::from_be_bytes(id))
+}
+
+fn hmac(secret: &[u8]) -> Result<HmacSha256, &'static str> {
+    HmacSha256::new_from_slice(secret).map_err(|_| "Invalid token secret.")
+}
@@ -1,23 +1,111 @@
 pub mod state {
+    use crate::memory::Memory;
     use crate::types::assets::AssetHashes;
-    use crate::types::store::{Asset, Batch, Chunk};
+    use crate::types::store::{Asset, AssetRevisions, Batch, Deploy, UploadedChunk};
     use candid::{CandidType, Deserialize, Principal};
-    use std::collections::HashMap;
+    use ic_stable_structures::StableBTreeMap;
+    use std::collections::{BTreeSet, HashMap};
 
     pub type Batches = HashMap<u128, Batch>;
-    pub type Chunks = HashMap<u128, Chunk>;
-    pub type Assets = HashMap<String, Asset>;
+    pub type Chunks = HashMap<u128, UploadedChunk>;
+    pub type Assets = StableBTreeMap<String, Asset, Memory>;
+    pub type ContentChunks = StableBTreeMap<u64, Vec<u8>, Memory>;
+    pub type Revisions = StableBTreeMap<String, AssetRevisions, Memory>;
+    pub type Permissions = HashMap<Principal, Role>;
+    pub type Deploys = HashMap<u128, Deploy>;
 
-    #[derive(Default, Clone)]
+    /// Access level of a principal on the bucket. Variants are ordered by privilege so that a
+    /// higher role implies every lower one (an owner can upload, an uploader can read).
+    #[derive(CandidType, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+    pub enum Role {
+        Reader,
+        Uploader,
+        Owner,
+    }
+
+    #[derive(Default)]
     pub struct State {
         pub stable: StableState,
+        pub heap: HeapState,
         pub runtime: RuntimeState,
     }
 
-    #[derive(Default, CandidType, Deserialize, Clone)]
+    /// Assets live in stable memory and are never serialized on upgrade. The bytes of the
+    /// assets are kept apart from their metadata, keyed by the ids referenced in each encoding.
     pub struct StableState {
-        pub user: Option<Principal>,
         pub assets: Assets,
+        pub content_chunks: ContentChunks,
+        // Previous versions of the live assets, by full path
+        pub revisions: Revisions,
+    }
+
+    /// Written in stable memory on upgrade. Candid rejects a missing field unless it is optional,
+    /// a field added after the first release must be an option so that the former state decodes.
+    #[derive(Default, CandidType, Deserialize, Clone)]
+    pub struct HeapState {
+        pub user: Option<Principal>,
+        pub permissions: Permissions,
+        pub quotas: Quotas,
+        // Full path of the asset served for the urls that match no asset, with a 404 unless it is
+        // the entry point of a single page app
+        pub fallback: Option<String>,
+        pub cache_control_rules: Vec<CacheControlRule>,
+        pub routing: RoutingConfig,
+        // Staged deploys are kept over upgrades as their content is already in stable memory
+        pub deploys: Deploys,
+        // Key of the signed access tokens, drawn on the first token issued
+        pub token_secret: Option<Vec<u8>>,
+        pub next_token_id: u64,
+        // Ids of the revoked access tokens with their expiry, after which they are forgotten
+        pub revoked_tokens: HashMap<u64, u64>,
+        // Key of the batch ids, drawn on the first upload
+        pub batch_secret: Option<Vec<u8>>,
+        // Batches and chunks created so far, kept over upgrades so that no id is handed out twice
+        pub batch_count: u128,
+        pub chunk_count: u128,
+        // Deploys created so far, the next deploy id
+        pub deploy_count: u128,
+        pub usage: StorageUsage,
+    }
+
+    /// How the urls that match no asset are resolved. `index_file` (e.g. "index.html") is served
+    /// for the url of its folder, with or without trailing slash ("/docs" and "/docs/" are both
+    /// certified), `html_extension` serves
+    /// "/about.html" for "/about" and `spa_fallback` serves the fallback asset with a 200 as the
+    /// entry point of a single page app. Exact paths always win.
+    #[derive(CandidType, Deserialize, Clone, Default)]
+    pub struct RoutingConfig {
+        pub index_file: Option<String>,
+        pub html_extension: bool,
+        pub spa_fallback: bool,
+    }
+
+    /// Cache-Control of the assets that match the pattern, unless they have their own. The
+    /// first matching rule applies.
+    #[derive(CandidType, Deserialize, Clone)]
+    pub struct CacheControlRule {
+        pub pattern: CacheControlPattern,
+        pub cache_control: String,
+    }
+
+    #[derive(CandidType, Deserialize, Clone)]
+    pub enum CacheControlPattern {
+        // Assets directly in the folder, not those of its subfolders which "/folder/**" matches
+        Folder(String),
+        // Full paths matching the glob, in which "*" and "?" do not match "/" but "**" does
+        Glob(String),
+    }
+
+    /// Limits of the bucket, sizes in bytes. `None` means unlimited.
+    #[derive(Default, CandidType, Deserialize, Clone, Debug)]
+    pub struct Quotas {
+        pub max_asset_size: Option<u128>,
+        pub max_bucket_size: Option<u128>,
+        pub max_chunk_size: Option<u128>,
+        // Batches opened and not yet committed by a same caller
+        pub max_batches_per_uploader: Option<u32>,
+        // Bytes stored in the bucket by a same uploader
+        pub max_uploader_size: Option<u128>,
     }
 
     #[derive(Default, Clone)]
@@ -25,6 +113,26 @@ pub mod state {
         pub chunks: Chunks,
         pub batches: Batches,
         pub asset_hashes: AssetHashes,
+        // Ids of the batches by expiry, the earliest first
+        pub batch_expiries: BTreeSet<(u64, u128)>,
+        pub sweep_stats: SweepStats,
+    }
+
+    /// Batches and chunks removed on expiry since the last upgrade
+    #[derive(Default, Clone)]
+    pub struct SweepStats {
+        pub sweeps: u64,
+        pub last_sweep_at: Option<u64>,
+        pub expired_batches: u64,
+        pub expired_chunks: u64,
+    }
+
+    /// Bytes of the content chunks in stable memory, kept over upgrades. Content uploaded before
+    /// the uploaders were tracked only counts for the bucket.
+    #[derive(Default, CandidType, Deserialize, Clone)]
+    pub struct StorageUsage {
+        pub bucket_size: u128,
+        pub uploaders: HashMap<Principal, u128>,
     }
 }
 
@@ -32,41 +140,76 @@ pub mod assets {
     use ic_certified_map::{Hash, RbTree};
     use std::clone::Clone;
 
-    #[derive(Default, Clone)]
+    #[derive(Clone)]
     pub struct AssetHashes {
         pub tree: RbTree<String, Hash>,
+        pub expr_tree: NestedTree,
+    }
+
+    pub type NestedTree = RbTree<Vec<u8>, NestedTreeNode>;
+
+    /// Node of the `http_expr` tree, which nests one level per segment of the urls
+    #[derive(Clone)]
+    pub enum NestedTreeNode {
+        Leaf(Vec<u8>),
+        Nested(NestedTree),
     }
 }
 
 pub mod store {
     use crate::types::http::HeaderField;
-    use candid::CandidType;
+    use crate::types::interface::TokenScope;
+    use candid::{CandidType, Principal};
     use ic_certified_map::Hash;
     use serde::Deserialize;
     use std::clone::Clone;
-    use std::collections::HashMap;
+    use std::collections::{BTreeMap, HashMap};
 
+    /// `index` is the position of the chunk in the content of the batch, the one after the last
+    /// chunk when `None`, below one million. A chunk uploaded again at the same index replaces the
+    /// former one.
     #[derive(CandidType, Deserialize, Clone)]
     pub struct Chunk {
         pub batch_id: u128,
         pub content: Vec<u8>,
+        pub index: Option<usize>,
+    }
+
+    /// Chunk kept in memory until its batch is committed
+    #[derive(Clone)]
+    pub struct UploadedChunk {
+        pub batch_id: u128,
+        pub content: Vec<u8>,
+        pub uploader: Principal,
     }
 
     #[derive(CandidType, Deserialize, Clone)]
     pub struct AssetEncoding {
         pub modified: u64,
-        pub content_chunks: Vec<Vec<u8>>,
+        // Ids of the chunks in the stable content chunks, in order
+        pub content_chunks: Vec<u64>,
+        // Byte length of each chunk, to map byte ranges onto the chunks without reading them
+        pub chunk_lengths: Vec<u128>,
+        // Hash of each chunk, the body of the partial content that answers a range of the chunk
+        pub chunk_sha256s: Vec<Hash>,
         pub total_length: u128,
         pub sha256: Hash,
+        // Caller who uploaded the content, to whom its bytes are counted
+        pub uploaded_by: Option<Principal>,
     }
 
     #[derive(CandidType, Deserialize, Clone, Debug)]
     pub struct AssetKey {
         pub name: String,
+        // Time of the commit of the raw content, set by the bucket
         pub created: u64,
+        // Path of the parent of the asset, derived from its full path on upload ("/" at the root)
         pub folder: String,
+        // Normalized on upload, absolute and without relative segments
         pub full_path: String,
+        // Marks the asset as protected, it is then served with a signed access token only
         pub id: Option<String>,
+        // Byte length of the raw content, set by the bucket
         pub size: u32,
         pub preview: Option<Vec<u8>>,
     }
@@ -75,39 +218,394 @@ pub mod store {
     pub struct Asset {
         pub key: AssetKey,
         pub headers: Vec<HeaderField>,
-        // Currently we use only raw data but we might use encoded chunks (gzip, compress) in the future to improve performance.
-        // At the same time we want to avoid to have to map the state on post-upgrade when we will do so. Therefore we use a convenient HashMap instead of a struct.
+        // The raw content and its encoded variants, by Content-Encoding ("raw", "gzip", "br"...)
         pub encodings: HashMap<String, AssetEncoding>,
+        // None for the assets committed before revisions were recorded
+        pub commit: Option<AssetCommit>,
+    }
+
+    /// Commit of the raw content of an asset. Encoded variants belong to the revision of their raw.
+    #[derive(CandidType, Deserialize, Clone)]
+    pub struct AssetCommit {
+        pub revision: u64,
+        pub committed_at: u64,
+        pub committed_by: Principal,
+    }
+
+    /// Versions an asset replaced, from the oldest. Only the latest ones are kept.
+    #[derive(CandidType, Deserialize, Clone, Default)]
+    pub struct AssetRevisions {
+        pub assets: Vec<Asset>,
+    }
+
+    /// Reason why no asset can be served for a url, each answered with its own status code
+    pub enum GetAssetError {
+        BadRequest(&'static str),
+        Forbidden(&'static str),
+        NotFound(&'static str),
+    }
+
+    /// Claims signed in an access token
+    #[derive(CandidType, Deserialize)]
+    pub struct TokenClaims {
+        pub token_id: u64,
+        pub full_path: String,
+        pub expires_at: u64,
+        pub scope: TokenScope,
     }
 
     #[derive(CandidType, Deserialize, Clone)]
     pub struct Batch {
         pub key: AssetKey,
         pub expires_at: u64,
+        pub uploader: Principal,
+        // Bytes of the chunks uploaded in the batch
+        pub size: u128,
+        // Ids of the chunks by index
+        pub chunks: BTreeMap<usize, u128>,
+    }
+
+    /// Assets and deletions staged to be applied all at once. The content of the staged assets is
+    /// written in stable memory but shared with no live asset.
+    #[derive(CandidType, Deserialize, Clone)]
+    pub struct Deploy {
+        pub assets: HashMap<String, Asset>,
+        pub deletions: Vec<String>,
+        pub expires_at: u64,
+        // Principal that created the deploy, the only one to stage into, commit or abort it
+        pub deployer: Principal,
     }
 }
 
 pub mod interface {
     use crate::types::http::HeaderField;
-    use candid::{CandidType, Deserialize};
+    use crate::types::state::{Quotas, Role};
+    use crate::types::store::AssetKey;
+    use candid::{CandidType, Deserialize, Principal};
+    use serde_bytes::ByteBuf;
+
+    /// Argument of the canister installation, also accepted optionally on upgrade to override
+    /// the owner, the admins and the quotas of the bucket.
+    #[derive(CandidType, Deserialize)]
+    pub struct BucketInitArgs {
+        pub owner: Principal,
+        pub admins: Vec<Principal>,
+        pub quotas: Option<Quotas>,
+    }
 
     #[derive(CandidType)]
     pub struct InitUpload {
         pub batch_id: u128,
     }
 
+    #[derive(CandidType)]
+    pub struct InitDeploy {
+        pub deploy_id: u128,
+    }
+
+    /// Previous version of an asset. Revision 0 is the version committed before revisions were
+    /// recorded.
+    #[derive(CandidType)]
+    pub struct AssetRevision {
+        pub revision: u64,
+        pub committed_at: Option<u64>,
+        pub committed_by: Option<Principal>,
+        pub headers: Vec<HeaderField>,
+        pub sha256: ByteBuf,
+        pub total_length: u128,
+        pub chunks_count: usize,
+    }
+
+    /// Chunk of the raw content of a revision
+    #[derive(CandidType, Deserialize)]
+    pub struct GetRevisionChunk {
+        pub full_path: String,
+        pub revision: u64,
+        pub index: usize,
+    }
+
     #[derive(CandidType)]
     pub struct UploadChunk {
         pub chunk_id: u128,
+        pub index: usize,
     }
 
+    /// Indices of the chunks uploaded so far, from which an interrupted upload resumes
+    #[derive(CandidType, Deserialize)]
+    pub struct BatchChunks {
+        pub batch_id: u128,
+        pub indices: Vec<usize>,
+        pub size: u128,
+        pub expires_at: u64,
+    }
+
+    /// Batch opened by the caller and neither committed nor expired yet
+    #[derive(CandidType, Deserialize)]
+    pub struct PendingBatch {
+        pub batch_id: u128,
+        pub full_path: String,
+        pub expires_at: u64,
+        // Bytes of the chunks uploaded so far
+        pub size: u128,
+    }
+
+    /// Upload batches awaiting their commit and those removed on expiry since the last upgrade
+    #[derive(CandidType, Deserialize)]
+    pub struct GcStats {
+        pub pending_batches: u64,
+        pub pending_chunks: u64,
+        pub next_expires_at: Option<u64>,
+        // Sweeps run by the timer or on demand
+        pub sweeps: u64,
+        pub last_sweep_at: Option<u64>,
+        pub expired_batches: u64,
+        pub expired_chunks: u64,
+    }
+
+    /// `encoding_type` is the `Content-Encoding` of the uploaded chunks (`gzip`, `deflate` or `br`),
+    /// `None` or `identity` for the raw content. Encoded variants are added to an asset whose raw
+    /// content was committed first and keep the headers of that raw commit. `sha256` and
+    /// `total_length` are those the client expects of the content of the chunks, in their order.
+    /// Empty `chunk_ids` commit the chunks of the batch in the order of their index.
     #[derive(CandidType, Deserialize)]
     pub struct CommitBatch {
         pub batch_id: u128,
         pub headers: Vec<HeaderField>,
         pub chunk_ids: Vec<u128>,
+        pub encoding_type: Option<String>,
+        pub sha256: Option<ByteBuf>,
+        pub total_length: Option<u128>,
     }
 
+    /// Failure of an upload, a deletion or a deploy
+    #[derive(CandidType, Deserialize, Debug)]
+    pub enum BucketError {
+        BatchNotFound {
+            batch_id: u128,
+        },
+        BatchExpired {
+            batch_id: u128,
+            expires_at: u64,
+        },
+        ChunkNotFound {
+            chunk_id: u128,
+        },
+        ChunkNotInBatch {
+            chunk_id: u128,
+            batch_id: u128,
+        },
+        EmptyBatch {
+            batch_id: u128,
+        },
+        UnsupportedEncoding {
+            encoding_type: String,
+        },
+        RawEncodingMissing {
+            full_path: String,
+        },
+        InvalidContent {
+            reason: String,
+        },
+        // The chunks do not assemble the content the client uploaded
+        Sha256Mismatch {
+            expected: ByteBuf,
+            actual: ByteBuf,
+        },
+        LengthMismatch {
+            expected: u128,
+            actual: u128,
+        },
+        AssetNotFound {
+            full_path: String,
+        },
+        InvalidToken {
+            full_path: String,
+        },
+        DeployNotFound {
+            deploy_id: u128,
+        },
+        DeployExpired {
+            deploy_id: u128,
+            expires_at: u64,
+        },
+        RevisionNotFound {
+            full_path: String,
+            revision: u64,
+        },
+        ChunkIndexOutOfRange {
+            index: usize,
+            chunks_count: usize,
+        },
+        FolderNotFound {
+            path: String,
+        },
+        DestinationExists {
+            full_path: String,
+        },
+        InvalidMove {
+            reason: String,
+        },
+        InvalidKey {
+            full_path: String,
+            reason: String,
+        },
+        InvalidExpiry {
+            expires_at: u64,
+            max_expires_at: u64,
+        },
+        InvalidAccessToken {
+            reason: String,
+        },
+        RandomnessUnavailable {
+            reason: String,
+        },
+        ChunkTooLarge {
+            size: u128,
+            max_size: u128,
+        },
+        AssetTooLarge {
+            size: u128,
+            max_size: u128,
+        },
+        TooManyBatches {
+            max_batches: u32,
+        },
+        BucketQuotaExceeded {
+            used: u128,
+            requested: u128,
+            max_size: u128,
+        },
+        UploaderQuotaExceeded {
+            uploader: Principal,
+            used: u128,
+            requested: u128,
+            max_size: u128,
+        },
+        BatchNotOwned {
+            batch_id: u128,
+        },
+        ChunkIndexMissing {
+            batch_id: u128,
+            index: usize,
+        },
+        DeployNotOwned {
+            deploy_id: u128,
+        },
+        InvalidPermission {
+            principal: Principal,
+            reason: String,
+        },
+        PermissionNotFound {
+            principal: Principal,
+        },
+        InvalidFallback {
+            full_path: String,
+            reason: String,
+        },
+        InvalidCacheControl {
+            reason: String,
+        },
+        InvalidRouting {
+            reason: String,
+        },
+        TooManyChunks {
+            batch_id: u128,
+            max_chunks: usize,
+        },
+    }
+
+    /// Page of the assets whose full path starts with `prefix`, after the `cursor` returned with
+    /// the previous page. `limit` defaults to 100 keys and is kept between 1 and 1000. Only the
+    /// `FullPath` order reads no more than the page, the other orders decode and sort every asset
+    /// of the prefix on each page.
+    #[derive(CandidType, Deserialize)]
+    pub struct ListParams {
+        pub prefix: Option<String>,
+        pub order: Option<ListOrder>,
+        pub cursor: Option<ListCursor>,
+        pub limit: Option<u32>,
+        pub omit_preview: bool,
+    }
+
+    /// Ascending order of the listing, ties are ordered by full path
+    #[derive(CandidType, Deserialize, Clone, Copy)]
+    pub enum ListOrder {
+        FullPath,
+        Created,
+        Size,
+        Name,
+    }
+
+    /// Position of the last key of a page, whichever the order
+    #[derive(CandidType, Deserialize, Clone)]
+    pub struct ListCursor {
+        pub full_path: String,
+        pub created: u64,
+        pub size: u32,
+        pub name: String,
+    }
+
+    /// Signed token granting access to the protected asset at `full_path`, or to the protected
+    /// assets under the folder `full_path`, until `expires_at` (at most 30 days from now)
+    #[derive(CandidType, Deserialize)]
+    pub struct CreateAccessToken {
+        pub full_path: String,
+        pub expires_at: u64,
+        pub scope: Option<TokenScope>,
+    }
+
+    #[derive(CandidType, Deserialize, Clone, Copy, PartialEq, Eq)]
+    pub enum TokenScope {
+        Asset,
+        Folder,
+    }
+
+    /// The token is passed as `?token=` in the url of the asset
+    #[derive(CandidType)]
+    pub struct AccessToken {
+        pub token: String,
+        pub token_id: u64,
+        pub expires_at: u64,
+    }
+
+    /// Immediate children of a folder
+    #[derive(CandidType)]
+    pub struct FolderListing {
+        pub files: Vec<AssetKey>,
+        pub folders: Vec<FolderStats>,
+    }
+
+    /// Raw size and count of the assets under a folder, at any depth
+    #[derive(CandidType)]
+    pub struct FolderStats {
+        pub path: String,
+        pub size: u128,
+        pub count: u64,
+    }
+
+    #[derive(CandidType)]
+    pub struct ListResults {
+        pub keys: Vec<AssetKey>,
+        // None on the last page
+        pub next_cursor: Option<ListCursor>,
+    }
+
+    /// Usage of the bucket and of the caller against the quotas
+    #[derive(CandidType, Deserialize)]
+    pub struct Usage {
+        pub quotas: Quotas,
+        pub bucket_size: u128,
+        pub uploader_size: u128,
+        pub uploader_batches: u32,
+    }
+
+    #[derive(CandidType, Deserialize)]
+    pub struct Permission {
+        pub principal: Principal,
+        pub role: Role,
+    }
+
+    /// `token` is a signed access token to the asset when it is protected
     #[derive(CandidType, Deserialize)]
     pub struct Del {
         pub full_path: String,
@@ -118,6 +616,7 @@ pub mod interface {
 pub mod http {
     use candid::{define_function, CandidType, Deserialize};
     use serde_bytes::ByteBuf;
+    use std::collections::HashMap;
 
     #[derive(CandidType, Deserialize, Clone)]
     pub struct HeaderField(pub String, pub String);
@@ -128,6 +627,20 @@ pub mod http {
         pub method: String,
         pub headers: Vec<HeaderField>,
         pub body: Vec<u8>,
+        pub certificate_version: Option<u16>,
+    }
+
+    pub struct RequestUrl {
+        pub path: String,
+        pub params: HashMap<String, String>,
+    }
+
+    /// Failure of the bucket to answer a request it is valid to serve
+    pub enum ServerError {
+        // Only the query calls receive a certificate
+        CertificateUnavailable,
+        CertificateEncoding,
+        ContentMissing,
     }
 
     #[derive(CandidType, Deserialize, Clone)]
@@ -150,10 +663,18 @@ pub mod http {
     #[derive(CandidType, Deserialize, Clone)]
     pub struct StreamingCallbackToken {
         pub full_path: String,
+        pub encoding_type: String,
         pub token: Option<String>,
         pub headers: Vec<HeaderField>,
         pub sha256: Option<ByteBuf>,
-        pub index: usize,
+        pub range: StreamingRange,
+    }
+
+    /// Remaining bytes of the streamed content, `offset` and `end` included
+    #[derive(CandidType, Deserialize, Clone)]
+    pub struct StreamingRange {
+        pub offset: u128,
+        pub end: u128,
     }
 
     #[derive(CandidType, Deserialize, Clone)]
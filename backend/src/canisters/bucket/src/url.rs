@@ -0,0 +1,142 @@
+use percent_encoding::percent_decode_str;
+use std::collections::HashMap;
+
+use crate::types::http::RequestUrl;
+
+/// Decoded path and query parameters of a request url. The path is made absolute, its empty,
+/// "." and ".." segments resolved, and keeps its trailing slash, which gateways certify as an
+/// empty last segment. The fragment, which browsers do not send, is ignored.
+pub fn parse_url(url: &str) -> Result<RequestUrl, &'static str> {
+    let url = url.split('#').next().unwrap_or_default();
+
+    let (path, query) = match url.split_once('?') {
+        Some((path, query)) => (path, query),
+        None => (url, ""),
+    };
+
+    Ok(RequestUrl {
+        path: normalize_path(&decode(path)?),
+        params: parse_query(query)?,
+    })
+}
+
+fn normalize_path(path: &str) -> String {
+    let mut segments: Vec<&str> = Vec::new();
+    let mut trailing_slash = false;
+
+    for segment in path.split('/') {
+        // A path that ends with a folder, e.g. "/docs/" or "/docs/.", has a trailing slash
+        trailing_slash = matches!(segment, "" | "." | "..");
+
+        match segment {
+            "" | "." => (),
+            ".." => {
+                segments.pop();
+            }
+            _ => segments.push(segment),
+        }
+    }
+
+    match (segments.is_empty(), trailing_slash) {
+        (false, true) => format!("/{}/", segments.join("/")),
+        _ => format!("/{}", segments.join("/")),
+    }
+}
+
+// The first value of a parameter wins, a parameter without value is empty
+fn parse_query(query: &str) -> Result<HashMap<String, String>, &'static str> {
+    let mut params = HashMap::new();
+
+    for param in query.split('&').filter(|param| !param.is_empty()) {
+        let (name, value) = param.split_once('=').unwrap_or((param, ""));
+
+        params
+            .entry(decode_query_component(name)?)
+            .or_insert(decode_query_component(value)?);
+    }
+
+    Ok(params)
+}
+
+// In a query, "+" is a space as in form submissions
+fn decode_query_component(component: &str) -> Result<String, &'static str> {
+    decode(&component.replace('+', " "))
+}
+
+fn decode(component: &str) -> Result<String, &'static str> {
+    percent_decode_str(component)
+        .decode_utf8()
+        .map(|decoded| decoded.into_owned())
+        .map_err(|_| "Invalid url encoding.")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_url;
+
+    fn path(url: &str) -> String {
+        parse_url(url).unwrap().path
+    }
+
+    fn param(url: &str, name: &str) -> Option<String> {
+        parse_url(url).unwrap().params.get(name).cloned()
+    }
+
+    #[test]
+    fn decodes_the_path() {
+        assert_eq!(path("/hello%20world.txt"), "/hello world.txt");
+        assert_eq!(path("/caf%C3%A9"), "/café");
+        assert!(parse_url("/%FF").is_err());
+    }
+
+    #[test]
+    fn splits_encoded_slashes() {
+        assert_eq!(path("/images%2Flogo.png"), "/images/logo.png");
+        assert_eq!(path("%2Fimages%2F"), "/images/");
+    }
+
+    #[test]
+    fn resolves_dot_segments() {
+        assert_eq!(path("/docs/../index.html"), "/index.html");
+        assert_eq!(path("/docs/./index.html"), "/docs/index.html");
+        assert_eq!(path("/../../index.html"), "/index.html");
+        assert_eq!(path("/docs/%2E%2E/index.html"), "/index.html");
+        assert_eq!(path("//docs//index.html"), "/docs/index.html");
+        assert_eq!(path("index.html"), "/index.html");
+    }
+
+    #[test]
+    fn keeps_the_trailing_slash_of_folders() {
+        assert_eq!(path("/docs/"), "/docs/");
+        assert_eq!(path("/docs/."), "/docs/");
+        assert_eq!(path("/docs/guide/.."), "/docs/");
+        assert_eq!(path("/docs"), "/docs");
+        assert_eq!(path("/"), "/");
+        assert_eq!(path(""), "/");
+        assert_eq!(path("/.."), "/");
+    }
+
+    #[test]
+    fn ignores_the_fragment() {
+        assert_eq!(path("/index.html#top"), "/index.html");
+        assert_eq!(
+            param("/index.html?token=abc#top", "token").as_deref(),
+            Some("abc")
+        );
+    }
+
+    #[test]
+    fn decodes_plus_as_a_space_in_the_query_only() {
+        assert_eq!(path("/a+b.txt"), "/a+b.txt");
+        assert_eq!(param("/?q=a+b", "q").as_deref(), Some("a b"));
+        assert_eq!(param("/?q=a%2Bb", "q").as_deref(), Some("a+b"));
+    }
+
+    #[test]
+    fn keeps_the_first_value_of_a_parameter() {
+        assert_eq!(param("/?token=a&token=b", "token").as_deref(), Some("a"));
+        assert_eq!(param("/?flag", "flag").as_deref(), Some(""));
+        assert_eq!(param("/?a=1&&b=2", "b").as_deref(), Some("2"));
+        assert_eq!(param("/?a=1", "b"), None);
+    }
+}
@@ -1,10 +1,11 @@
 use candid::{CandidType, Decode, Deserialize, Encode, Principal};
-use ic_cdk::api::management_canister::main::{
-    canister_info, canister_status as ic_canister_status, create_canister,
-    install_code as ic_install_code, CanisterIdRecord, CanisterInfoRequest, CanisterInstallMode,
-    CanisterSettings, CreateCanisterArgument, InstallCodeArgument,
+use ic_cdk::api::{canister_self, msg_caller};
+use ic_cdk::management_canister::{
+    canister_info, canister_status as ic_canister_status, create_canister_with_extra_cycles,
+    install_code as ic_install_code, CanisterInfoArgs, CanisterInstallMode, CanisterSettings,
+    CanisterStatusArgs, CreateCanisterArgs, InstallCodeArgs,
 };
-use ic_cdk::{caller, id, println};
+use ic_cdk::println;
 use ic_cdk::{init, query, update};
 use ic_stable_structures::{
     memory_manager::{MemoryId, MemoryManager, VirtualMemory},
@@ -38,6 +39,23 @@ pub const fn api_error(err_type: ApiErrorType, err_msg: String) -> ApiError {
     ApiError { err_type, err_msg }
 }
 
+/// Mirror of the bucket `BucketInitArgs`, encoded as the install and upgrade argument
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BucketInitArgs {
+    owner: Principal,
+    admins: Vec<Principal>,
+    quotas: Option<BucketQuotas>,
+}
+
+#[derive(CandidType, Deserialize, Clone, Debug)]
+pub struct BucketQuotas {
+    max_asset_size: Option<u128>,
+    max_bucket_size: Option<u128>,
+    max_chunk_size: Option<u128>,
+    max_batches_per_uploader: Option<u32>,
+    max_uploader_size: Option<u128>,
+}
+
 const MAX_VALUE_SIZE: u32 = 100;
 const MAX_KEY_SIZE: u32 = 30;
 
@@ -45,7 +63,7 @@ const MAX_KEY_SIZE: u32 = 30;
 struct Key(String);
 
 impl Storable for Key {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         self.0.to_bytes()
     }
 
@@ -67,7 +85,7 @@ struct SpawnCanister {
 }
 
 impl Storable for SpawnCanister {
-    fn to_bytes(&self) -> std::borrow::Cow<[u8]> {
+    fn to_bytes(&self) -> std::borrow::Cow<'_, [u8]> {
         Cow::Owned(Encode!(self).unwrap())
     }
 
@@ -105,7 +123,7 @@ pub fn init() {
 
     OWNER.with_borrow_mut(|owner| {
         owner
-            .set(Key(caller().to_string()))
+            .set(Key(msg_caller().to_string()))
             .expect("Failed to set the owner");
     });
 }
@@ -117,8 +135,9 @@ const BUCKET_WASM: &[u8] =
 async fn upgrade_canister(
     canister_principal: Principal,
     status: Option<CanisterInstallMode>,
+    init_args: Option<BucketInitArgs>,
 ) -> Result<(), ApiError> {
-    let caller = caller();
+    let caller = msg_caller();
     println!("upgrading cdn canister with the id {canister_principal} called by {caller}");
 
     let owner = OWNER.with_borrow(|ow| ow.get().clone());
@@ -147,16 +166,23 @@ async fn upgrade_canister(
     //
     println!("upgrading cdn canister with the id {canister_principal}");
 
-    let arg = InstallCodeArgument {
+    // post_upgrade takes optional overrides, a (re)install needs the full init arguments
+    let encoded_args = match install_status {
+        CanisterInstallMode::Upgrade(_) => Encode!(&init_args),
+        _ => Encode!(&init_args.unwrap_or_else(|| bucket_init_args(caller))),
+    }
+    .map_err(|e| api_error(ApiErrorType::BadRequest, e.to_string()))?;
+
+    let arg = InstallCodeArgs {
         mode: install_status,
         canister_id,
         wasm_module: BUCKET_WASM.to_vec(),
-        arg: vec![],
+        arg: encoded_args,
     };
 
-    ic_install_code(arg).await.map_err(|e| ApiError {
+    ic_install_code(&arg).await.map_err(|e| ApiError {
         err_type: ApiErrorType::BadRequest,
-        err_msg: e.1,
+        err_msg: e.to_string(),
     })?;
 
     println!(
@@ -169,7 +195,7 @@ async fn upgrade_canister(
     //
 
     // canister status
-    let c_status = ic_canister_status(CanisterIdRecord { canister_id })
+    let c_status = ic_canister_status(&CanisterStatusArgs { canister_id })
         .await
         .map_err(|_| {
             api_error(
@@ -180,7 +206,7 @@ async fn upgrade_canister(
 
     let pd = SpawnCanister {
         id: existing_canister.id,
-        hash: c_status.0.module_hash,
+        hash: c_status.module_hash,
         version: existing_canister.version + 1,
     };
 
@@ -193,7 +219,7 @@ async fn upgrade_canister(
 
 #[update]
 async fn spawn_bucket() -> Result<SpawnCanister, ApiError> {
-    let caller = caller();
+    let caller = msg_caller();
     let owner = OWNER.with(|ow| ow.borrow().get().clone());
 
     if caller.to_string() != owner.0 {
@@ -201,13 +227,13 @@ async fn spawn_bucket() -> Result<SpawnCanister, ApiError> {
     }
     let owner_principal = owner.0;
     println!("{owner_principal:?}");
-    let canister_settings = CreateCanisterArgument {
+    let canister_settings = CreateCanisterArgs {
         settings: Some(CanisterSettings {
-            controllers: Some(vec![caller, id()]),
+            controllers: Some(vec![caller, canister_self()]),
             ..Default::default()
         }),
     };
-    let new_canister = create_canister(canister_settings, DEFAULT_CYCLES).await;
+    let new_canister = create_canister_with_extra_cycles(&canister_settings, DEFAULT_CYCLES).await;
     let canister = new_canister.map_err(|_| {
         api_error(
             ApiErrorType::BadRequest,
@@ -216,16 +242,18 @@ async fn spawn_bucket() -> Result<SpawnCanister, ApiError> {
     })?;
 
     println!("{:?}", "works1");
-    let new_canister_principal = canister.0.canister_id;
+    let new_canister_principal = canister.canister_id;
     println!("{:?}", new_canister_principal.to_string());
-    let arg = InstallCodeArgument {
+    let encoded_args = Encode!(&bucket_init_args(caller))
+        .map_err(|e| api_error(ApiErrorType::BadRequest, e.to_string()))?;
+    let arg = InstallCodeArgs {
         mode: CanisterInstallMode::Install,
         canister_id: new_canister_principal,
         wasm_module: BUCKET_WASM.to_vec(),
-        arg: vec![],
+        arg: encoded_args,
     };
 
-    ic_install_code(arg).await.map_err(|_| {
+    ic_install_code(&arg).await.map_err(|_| {
         api_error(
             ApiErrorType::BadRequest,
             String::from("canister installation failed..."),
@@ -234,7 +262,7 @@ async fn spawn_bucket() -> Result<SpawnCanister, ApiError> {
 
     println!("{:?}", "works2");
 
-    let c_status = ic_canister_status(CanisterIdRecord {
+    let c_status = ic_canister_status(&CanisterStatusArgs {
         canister_id: new_canister_principal,
     })
     .await
@@ -248,7 +276,7 @@ async fn spawn_bucket() -> Result<SpawnCanister, ApiError> {
     println!("{:?}", "works3");
     let sc = SpawnCanister {
         id: new_canister_principal,
-        hash: c_status.0.module_hash,
+        hash: c_status.module_hash,
         version: 1,
     };
     println!("{:?}", "works1");
@@ -261,6 +289,15 @@ async fn spawn_bucket() -> Result<SpawnCanister, ApiError> {
     Ok(sc)
 }
 
+/// The caller spawning the bucket becomes its owner and the container stays an admin
+fn bucket_init_args(owner: Principal) -> BucketInitArgs {
+    BucketInitArgs {
+        owner,
+        admins: vec![canister_self()],
+        quotas: None,
+    }
+}
+
 //
 // list_buckets
 // CDN
@@ -279,14 +316,14 @@ fn list_buckets() -> Vec<SpawnCanister> {
 
 #[update]
 async fn get_controllers(cid: Principal) -> Vec<Principal> {
-    let canister_info = canister_info(CanisterInfoRequest {
+    let canister_info = canister_info(&CanisterInfoArgs {
         canister_id: cid,
         num_requested_changes: None,
     })
     .await
     .unwrap();
 
-    canister_info.0.controllers
+    canister_info.controllers
 }
 
 #[query]